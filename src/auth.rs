@@ -0,0 +1,371 @@
+// Auth :: OAuth2 access tokens for the Google Drive API.
+//
+// Access tokens only live for about an hour, so they are fetched on demand from a long-lived
+// credential and cached until shortly before they expire. One `Authenticator` is shared by all
+// FUSE worker threads.
+//
+// Two kinds of credential are supported: an OAuth refresh token for a user account, and a
+// service-account key, which signs its own JWT assertions and so needs no browser consent.
+//
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::RwLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use reqwest::blocking::Client;
+use serde::{Deserialize, Serialize};
+
+/// Google's default OAuth2 token endpoint.
+pub const DEFAULT_TOKEN_URI: &str = "https://oauth2.googleapis.com/token";
+
+/// Tokens are refreshed this long before they actually expire, so that a token handed out is
+/// still good by the time the request using it reaches the server.
+const EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+/// OAuth scope requested by service accounts for mounting.
+pub const DRIVE_READONLY_SCOPE: &str = "https://www.googleapis.com/auth/drive.readonly";
+
+/// OAuth scope requested by service accounts for commands which modify chunks.
+pub const DRIVE_SCOPE: &str = "https://www.googleapis.com/auth/drive";
+
+/// Lifetime requested for service-account assertions; Google allows at most one hour.
+const ASSERTION_LIFETIME: Duration = Duration::from_secs(3600);
+
+#[derive(Debug)]
+pub enum AuthError {
+    Io(io::Error),
+    Parse(serde_json::Error),
+    /// The credentials file has a `type` we don't know how to use.
+    UnsupportedCredentials(String),
+    /// The service-account private key could not be loaded or used for signing.
+    Key(jsonwebtoken::errors::Error),
+    Http(reqwest::Error),
+    /// The token endpoint answered, but not with a token.
+    Rejected { status: reqwest::StatusCode, body: String },
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Io(e) => write!(f, "cannot read credentials: {}", e),
+            AuthError::Parse(e) => write!(f, "cannot parse credentials: {}", e),
+            AuthError::UnsupportedCredentials(kind) =>
+                write!(f, "unsupported credentials type {:?}", kind),
+            AuthError::Key(e) => write!(f, "service account key: {}", e),
+            AuthError::Http(e) => write!(f, "token request failed: {}", e),
+            AuthError::Rejected { status, body } =>
+                write!(f, "token endpoint returned {}: {}", status, body.trim()),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// A freshly minted access token.
+pub struct Token {
+    pub access_token: String,
+    pub expires_at: Instant,
+}
+
+/// Something which can mint new access tokens from a long-lived credential.
+pub trait TokenSource: Send + Sync {
+    fn fetch(&self, client: &Client) -> Result<Token, AuthError>;
+}
+
+/// Caches the token from a `TokenSource` and refreshes it before it expires.
+pub struct Authenticator {
+    source: Box<dyn TokenSource>,
+    client: Client,
+    cached: RwLock<Option<Token>>,
+}
+
+impl Authenticator {
+    pub fn new(source: Box<dyn TokenSource>, client: Client) -> Authenticator {
+        Authenticator {
+            source,
+            client,
+            cached: RwLock::new(None),
+        }
+    }
+
+    /// Return a usable access token, fetching a new one if needed.
+    ///
+    /// While the token is good, threads only share a read lock. The write lock is held across a
+    /// refresh, so when the token runs out only one thread refreshes it and the rest wait for the
+    /// result.
+    pub fn access_token(&self) -> Result<String, AuthError> {
+        let usable = |cached: &Option<Token>| cached.as_ref()
+            .filter(|token| Instant::now() + EXPIRY_MARGIN < token.expires_at)
+            .map(|token| token.access_token.clone());
+        if let Some(access_token) = usable(&self.cached.read().unwrap()) {
+            return Ok(access_token);
+        }
+        let mut cached = self.cached.write().unwrap();
+        if let Some(access_token) = usable(&cached) {
+            return Ok(access_token);
+        }
+        debug!("fetching new access token");
+        let token = self.source.fetch(&self.client)?;
+        let access_token = token.access_token.clone();
+        *cached = Some(token);
+        Ok(access_token)
+    }
+
+    /// Drop `rejected` from the cache because the server refused it.
+    ///
+    /// If another thread has already replaced it, the newer token is kept.
+    pub fn invalidate(&self, rejected: &str) {
+        let mut cached = self.cached.write().unwrap();
+        if cached.as_ref().is_some_and(|t| t.access_token == rejected) {
+            *cached = None;
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+/// POST `params` to the token endpoint and parse the token out of the reply.
+fn request_token(client: &Client, token_uri: &str, params: &[(&str, &str)]) -> Result<Token, AuthError> {
+    let requested_at = Instant::now();
+    let resp = client
+        .post(token_uri)
+        .form(params)
+        .send()
+        .map_err(AuthError::Http)?;
+    let status = resp.status();
+    let body = resp.text().map_err(AuthError::Http)?;
+    if !status.is_success() {
+        return Err(AuthError::Rejected { status, body });
+    }
+    let token: TokenResponse = serde_json::from_str(&body).map_err(AuthError::Parse)?;
+    Ok(Token {
+        access_token: token.access_token,
+        expires_at: requested_at + Duration::from_secs(token.expires_in),
+    })
+}
+
+/// User credentials from an OAuth consent flow: exchanges a refresh token for access tokens.
+#[derive(Deserialize)]
+pub struct RefreshTokenSource {
+    client_id: String,
+    client_secret: String,
+    refresh_token: String,
+    #[serde(default = "default_token_uri")]
+    token_uri: String,
+}
+
+fn default_token_uri() -> String {
+    DEFAULT_TOKEN_URI.to_owned()
+}
+
+impl TokenSource for RefreshTokenSource {
+    fn fetch(&self, client: &Client) -> Result<Token, AuthError> {
+        request_token(client, &self.token_uri, &[
+            ("grant_type", "refresh_token"),
+            ("client_id", &self.client_id),
+            ("client_secret", &self.client_secret),
+            ("refresh_token", &self.refresh_token),
+        ])
+    }
+}
+
+/// A service-account key file, as downloaded from the Cloud console.
+#[derive(Deserialize)]
+struct ServiceAccountKey {
+    client_email: String,
+    private_key: String,
+    #[serde(default)]
+    private_key_id: Option<String>,
+    #[serde(default = "default_token_uri")]
+    token_uri: String,
+}
+
+#[derive(Serialize)]
+struct Claims<'a> {
+    iss: &'a str,
+    scope: &'a str,
+    aud: &'a str,
+    iat: u64,
+    exp: u64,
+}
+
+/// Service-account credentials: signs an RS256 JWT assertion and trades it for an access token.
+pub struct ServiceAccountSource {
+    client_email: String,
+    scope: String,
+    key: EncodingKey,
+    key_id: Option<String>,
+    token_uri: String,
+}
+
+impl ServiceAccountSource {
+    fn new(key: ServiceAccountKey, scope: &str) -> Result<ServiceAccountSource, AuthError> {
+        Ok(ServiceAccountSource {
+            key: EncodingKey::from_rsa_pem(key.private_key.as_bytes()).map_err(AuthError::Key)?,
+            client_email: key.client_email,
+            scope: scope.to_owned(),
+            key_id: key.private_key_id,
+            token_uri: key.token_uri,
+        })
+    }
+
+    fn assertion(&self) -> Result<String, AuthError> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let claims = Claims {
+            iss: &self.client_email,
+            scope: &self.scope,
+            aud: &self.token_uri,
+            iat: now,
+            exp: now + ASSERTION_LIFETIME.as_secs(),
+        };
+        let header = Header {
+            kid: self.key_id.clone(),
+            ..Header::new(Algorithm::RS256)
+        };
+        jsonwebtoken::encode(&header, &claims, &self.key).map_err(AuthError::Key)
+    }
+}
+
+impl TokenSource for ServiceAccountSource {
+    fn fetch(&self, client: &Client) -> Result<Token, AuthError> {
+        let assertion = self.assertion()?;
+        request_token(client, &self.token_uri, &[
+            ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+            ("assertion", &assertion),
+        ])
+    }
+}
+
+#[derive(Deserialize)]
+struct CredentialsKind {
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+/// Load a Google credentials JSON file and build an `Authenticator` for it.
+///
+/// Supports `authorized_user` files, as written by `gcloud auth application-default login`, and
+/// `service_account` key files. If `token_uri` is given it replaces the endpoint named in the
+/// file. `scope` is what service accounts ask for; user credentials have whatever scope they
+/// were granted.
+pub fn load_credentials(path: impl AsRef<Path>, token_uri: Option<&str>, scope: &str, client: Client) -> Result<Authenticator, AuthError> {
+    let text = fs::read_to_string(path).map_err(AuthError::Io)?;
+    let kind: CredentialsKind = serde_json::from_str(&text).map_err(AuthError::Parse)?;
+    let source: Box<dyn TokenSource> = match kind.kind.as_str() {
+        "authorized_user" => {
+            let mut source: RefreshTokenSource = serde_json::from_str(&text).map_err(AuthError::Parse)?;
+            if let Some(uri) = token_uri {
+                source.token_uri = uri.to_owned();
+            }
+            Box::new(source)
+        }
+        "service_account" => {
+            let mut key: ServiceAccountKey = serde_json::from_str(&text).map_err(AuthError::Parse)?;
+            if let Some(uri) = token_uri {
+                key.token_uri = uri.to_owned();
+            }
+            Box::new(ServiceAccountSource::new(key, scope)?)
+        }
+        _ => return Err(AuthError::UnsupportedCredentials(kind.kind)),
+    };
+    Ok(Authenticator::new(source, client))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stand_in::{self, StandIn};
+
+    fn client() -> Client {
+        Client::builder().no_proxy().build().unwrap()
+    }
+
+    fn token_response(access_token: &str, expires_in: u64) -> Vec<u8> {
+        stand_in::json(serde_json::json!({
+            "access_token": access_token,
+            "expires_in": expires_in,
+            "token_type": "Bearer",
+        }))
+    }
+
+    fn credentials(dir: &Path, json: serde_json::Value) -> std::path::PathBuf {
+        let path = dir.join("credentials.json");
+        fs::write(&path, json.to_string()).unwrap();
+        path
+    }
+
+    #[test]
+    fn refresh_token_is_exchanged_again_before_expiry() {
+        let server = StandIn::serve(vec![token_response("ya29.first", 30), token_response("ya29.second", 3600)]);
+        let dir = tempfile::tempdir().unwrap();
+        let path = credentials(dir.path(), serde_json::json!({
+            "type": "authorized_user",
+            "client_id": "id.apps.googleusercontent.com",
+            "client_secret": "secret",
+            "refresh_token": "1//refresh",
+        }));
+        let token_uri = format!("{}/token", server.url);
+        let auth = load_credentials(&path, Some(&token_uri), DRIVE_READONLY_SCOPE, client()).unwrap();
+
+        assert_eq!(auth.access_token().unwrap(), "ya29.first");
+        // Within `EXPIRY_MARGIN` of expiring, so not handed out again.
+        assert_eq!(auth.access_token().unwrap(), "ya29.second");
+        assert_eq!(auth.access_token().unwrap(), "ya29.second");
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        for request in &requests {
+            assert_eq!((request.method.as_str(), request.path.as_str()), ("POST", "/token"));
+            let form = request.form();
+            assert_eq!(form["grant_type"], "refresh_token");
+            assert_eq!(form["client_id"], "id.apps.googleusercontent.com");
+            assert_eq!(form["client_secret"], "secret");
+            assert_eq!(form["refresh_token"], "1//refresh");
+        }
+    }
+
+    #[test]
+    fn service_account_signs_an_assertion_for_the_token_endpoint() {
+        let server = StandIn::serve(vec![token_response("ya29.service", 3600)]);
+        let dir = tempfile::tempdir().unwrap();
+        let path = credentials(dir.path(), serde_json::json!({
+            "type": "service_account",
+            "client_email": "mounter@project.iam.gserviceaccount.com",
+            "private_key": include_str!("../testdata/service-account-key.pem"),
+            "private_key_id": "key-1",
+            "token_uri": "https://oauth2.googleapis.com/token",
+        }));
+        let token_uri = format!("{}/token", server.url);
+        let auth = load_credentials(&path, Some(&token_uri), DRIVE_READONLY_SCOPE, client()).unwrap();
+        assert_eq!(auth.access_token().unwrap(), "ya29.service");
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        let form = requests[0].form();
+        assert_eq!(form["grant_type"], "urn:ietf:params:oauth:grant-type:jwt-bearer");
+
+        #[derive(Deserialize)]
+        struct Claims {
+            iss: String,
+            scope: String,
+            iat: u64,
+            exp: u64,
+        }
+        let key = jsonwebtoken::DecodingKey::from_rsa_pem(include_bytes!("../testdata/service-account-key.pub.pem")).unwrap();
+        let mut validation = jsonwebtoken::Validation::new(Algorithm::RS256);
+        validation.set_audience(&[&token_uri]);
+        let assertion = jsonwebtoken::decode::<Claims>(&form["assertion"], &key, &validation).unwrap();
+        assert_eq!(assertion.header.kid.as_deref(), Some("key-1"));
+        assert_eq!(assertion.claims.iss, "mounter@project.iam.gserviceaccount.com");
+        assert_eq!(assertion.claims.scope, DRIVE_READONLY_SCOPE);
+        assert_eq!(assertion.claims.exp - assertion.claims.iat, ASSERTION_LIFETIME.as_secs());
+    }
+}
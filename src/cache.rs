@@ -0,0 +1,226 @@
+// BlockCache :: Size-bounded in-memory LRU cache of chunk data.
+//
+// Chunks are divided into fixed-size blocks aligned to the start of the chunk, and whole blocks
+// are cached, keyed by chunk ID and block index. Once the total size of cached blocks goes over
+// the capacity, the least recently used blocks are evicted.
+//
+// Every FUSE worker thread goes through the cache on every read, so it is split into shards by
+// key, each with its own lock, LRU list and an equal share of the capacity. Eviction is only
+// least recently used within a shard, which is close enough with keys spread evenly.
+//
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+type BlockKey = (String, u64);
+
+/// Most shards a cache is split into.
+const MAX_SHARDS: usize = 16;
+
+/// A shard holds at least this many blocks, so that small caches are split less.
+const MIN_SHARD_BLOCKS: usize = 4;
+
+pub struct BlockCache {
+    block_size: u64,
+    /// Capacity of each shard, in bytes.
+    shard_capacity: usize,
+    shards: Vec<Mutex<Lru>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub blocks: usize,
+    pub bytes: usize,
+}
+
+#[derive(Default)]
+struct Lru {
+    /// Each block with the tick at which it was last used.
+    blocks: HashMap<BlockKey, (Arc<Vec<u8>>, u64)>,
+    /// Keys by last use, oldest first.
+    by_use: BTreeMap<u64, BlockKey>,
+    tick: u64,
+    bytes: usize,
+}
+
+impl Lru {
+    fn touch(&mut self, key: &BlockKey) -> Option<Arc<Vec<u8>>> {
+        self.tick += 1;
+        let tick = self.tick;
+        let (data, last_use) = self.blocks.get_mut(key)?;
+        let key = self.by_use.remove(last_use).unwrap();
+        *last_use = tick;
+        self.by_use.insert(tick, key);
+        Some(Arc::clone(data))
+    }
+
+    fn remove(&mut self, key: &BlockKey) {
+        if let Some((data, last_use)) = self.blocks.remove(key) {
+            self.by_use.remove(&last_use);
+            self.bytes -= data.len();
+        }
+    }
+}
+
+impl BlockCache {
+    pub fn new(block_size: u64, capacity: usize) -> BlockCache {
+        assert!(block_size > 0, "block size must be positive");
+        let shards = (capacity / (block_size as usize).saturating_mul(MIN_SHARD_BLOCKS)).clamp(1, MAX_SHARDS);
+        BlockCache {
+            block_size,
+            shard_capacity: capacity / shards,
+            shards: (0..shards).map(|_| Mutex::new(Lru::default())).collect(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn shard(&self, key: &BlockKey) -> &Mutex<Lru> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    /// Look up block `index` of `chunk_id`, marking it as recently used.
+    pub fn get(&self, chunk_id: &str, index: u64) -> Option<Arc<Vec<u8>>> {
+        let key = (chunk_id.to_owned(), index);
+        let found = self.shard(&key).lock().unwrap().touch(&key);
+        let counter = if found.is_some() { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        found
+    }
+
+    /// Check whether block `index` of `chunk_id` is cached, without counting a hit or miss.
+    pub fn contains(&self, chunk_id: &str, index: u64) -> bool {
+        let key = (chunk_id.to_owned(), index);
+        self.shard(&key).lock().unwrap().blocks.contains_key(&key)
+    }
+
+    /// Add block `index` of `chunk_id`, evicting older blocks if the cache is over capacity.
+    pub fn insert(&self, chunk_id: &str, index: u64, data: Arc<Vec<u8>>) {
+        if data.len() > self.shard_capacity {
+            return;
+        }
+        let key = (chunk_id.to_owned(), index);
+        let mut lru = self.shard(&key).lock().unwrap();
+        lru.remove(&key);
+
+        lru.tick += 1;
+        let tick = lru.tick;
+        lru.bytes += data.len();
+        lru.by_use.insert(tick, key.clone());
+        lru.blocks.insert(key, (data, tick));
+
+        while lru.bytes > self.shard_capacity {
+            let oldest = match lru.by_use.first_key_value() {
+                Some((_, key)) => key.clone(),
+                None => break,
+            };
+            lru.remove(&oldest);
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let mut stats = CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            blocks: 0,
+            bytes: 0,
+        };
+        for shard in &self.shards {
+            let lru = shard.lock().unwrap();
+            stats.blocks += lru.blocks.len();
+            stats.bytes += lru.bytes;
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(len: usize) -> Arc<Vec<u8>> {
+        Arc::new(vec![0; len])
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        // Small enough to be a single shard, holding three blocks.
+        let cache = BlockCache::new(10, 30);
+        for index in 0..3 {
+            cache.insert("c", index, block(10));
+        }
+        assert!(cache.get("c", 0).is_some());
+        cache.insert("c", 3, block(10));
+        assert!(!cache.contains("c", 1));
+        assert!(cache.contains("c", 0) && cache.contains("c", 2) && cache.contains("c", 3));
+
+        cache.insert("c", 4, block(10));
+        assert!(!cache.contains("c", 2));
+    }
+
+    #[test]
+    fn stays_within_capacity() {
+        let cache = BlockCache::new(100, 10_000);
+        assert!(cache.shards.len() > 1);
+        for index in 0..1000 {
+            cache.insert(&format!("c{}", index % 7), index, block(100));
+            assert!(cache.stats().bytes <= 10_000);
+        }
+        let stats = cache.stats();
+        assert_eq!(stats.bytes, stats.blocks * 100);
+        assert!(stats.bytes > 5_000, "{:?}", stats);
+    }
+
+    #[test]
+    fn replacing_a_block_counts_it_once() {
+        let cache = BlockCache::new(10, 30);
+        cache.insert("c", 0, block(10));
+        cache.insert("c", 0, block(4));
+        let stats = cache.stats();
+        assert_eq!((stats.blocks, stats.bytes), (1, 4));
+        assert_eq!(cache.get("c", 0).unwrap().len(), 4);
+
+        // It is the newest block again, so the others go first.
+        cache.insert("c", 1, block(10));
+        cache.insert("c", 2, block(10));
+        cache.insert("c", 0, block(10));
+        cache.insert("c", 3, block(10));
+        assert!(!cache.contains("c", 1));
+        assert!(cache.contains("c", 0));
+    }
+
+    #[test]
+    fn oversized_blocks_are_not_cached() {
+        let cache = BlockCache::new(10, 30);
+        cache.insert("c", 0, block(10));
+        cache.insert("c", 1, block(31));
+        assert!(!cache.contains("c", 1));
+        assert!(cache.contains("c", 0));
+        assert_eq!(cache.stats().bytes, 10);
+    }
+
+    #[test]
+    fn counts_hits_and_misses() {
+        let cache = BlockCache::new(10, 30);
+        cache.insert("c", 0, block(10));
+        assert!(cache.get("c", 0).is_some());
+        assert!(cache.get("c", 0).is_some());
+        assert!(cache.get("c", 1).is_none());
+        assert!(cache.contains("c", 0) && !cache.contains("c", 1));
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (2, 1));
+    }
+}
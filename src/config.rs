@@ -0,0 +1,317 @@
+// Config :: Command-line subcommands and flags, and the config file they can also come from.
+//
+// Every option can be given as a flag, most also as a `PASSTHRUFS_*` environment variable, and
+// all of them in a TOML config file using the flag's name as the key (`cache-dir = "..."`). A flag
+// or environment variable wins over the config file; `-o` options from both are passed to FUSE.
+//
+// Keys themselves are only taken from a key file or the environment (PASSTHRUFS_KEY,
+// PASSTHRUFS_PASSPHRASE), never from flags, so that they do not show up in the process list.
+//
+
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use clap::{Args, Parser, Subcommand};
+use serde::Deserialize;
+
+#[derive(Debug, Parser)]
+#[command(name = "passthrufs", version, about = "Mount files stored as remote chunks")]
+pub struct Cli {
+    /// TOML file to read options from.
+    #[arg(long, short = 'c', global = true, env = "PASSTHRUFS_CONFIG", value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub options: Options,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Mount the virtual files, staying in the foreground until unmounted.
+    Mount {
+        /// Where to mount; overrides `mountpoint` in the config file.
+        mountpoint: Option<PathBuf>,
+    },
+    /// Unmount a mounted filesystem.
+    Unmount {
+        /// Where it is mounted; overrides `mountpoint` in the config file.
+        mountpoint: Option<PathBuf>,
+    },
+    /// Describe what the manifests say, without going to the backend.
+    Inspect {
+        /// Files to describe; all of them if none are given.
+        names: Vec<OsString>,
+    },
+    /// Check every chunk against the backend and the keys against the encrypted chunks.
+    Verify {
+        /// Files to check; all of them if none are given.
+        names: Vec<OsString>,
+    },
+    /// Re-encrypt encrypted chunks under the newest key, recording the new checksums.
+    Rotate {
+        /// Files to re-encrypt; all of them if none are given.
+        names: Vec<OsString>,
+    },
+}
+
+#[derive(Debug, Default, Args, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Options {
+    /// Directory holding the `*.json` manifests [default: manifests]
+    #[arg(long, global = true, env = "PASSTHRUFS_MANIFESTS", value_name = "DIR")]
+    pub manifests: Option<PathBuf>,
+
+    /// Directory other paths in the mount pass through to [default: target]
+    #[arg(long, global = true, env = "PASSTHRUFS_SOURCE", value_name = "DIR")]
+    pub source: Option<PathBuf>,
+
+    /// Only settable in the config file; `mount` and `unmount` take it as an argument.
+    #[arg(skip)]
+    pub mountpoint: Option<PathBuf>,
+
+    /// Read chunks from files in this directory instead of from Google Drive.
+    #[arg(long, global = true, env = "PASSTHRUFS_CHUNK_DIR", value_name = "DIR")]
+    pub chunk_dir: Option<PathBuf>,
+
+    /// Service account or authorized user credentials [default: credentials.json]
+    #[arg(long, global = true, env = "PASSTHRUFS_CREDENTIALS", value_name = "FILE")]
+    pub credentials: Option<PathBuf>,
+
+    /// Token endpoint to use instead of the one in the credentials.
+    #[arg(long, global = true, env = "PASSTHRUFS_TOKEN_URI", value_name = "URL")]
+    pub token_uri: Option<String>,
+
+    /// File holding the keys for encrypted chunks, newest first.
+    #[arg(long, global = true, env = "PASSTHRUFS_KEY_FILE", value_name = "FILE")]
+    pub key_file: Option<PathBuf>,
+
+    /// Also keep fetched data on disk, in this directory, across remounts.
+    #[arg(long, global = true, env = "PASSTHRUFS_CACHE_DIR", value_name = "DIR")]
+    pub cache_dir: Option<PathBuf>,
+
+    /// Size cap for the on-disk cache, in bytes [default: 16 GiB]
+    #[arg(long, global = true, env = "PASSTHRUFS_CACHE_SIZE", value_name = "BYTES")]
+    pub cache_size: Option<u64>,
+
+    /// Size cap for the in-memory cache, in bytes [default: 256 MiB]
+    #[arg(long, global = true, env = "PASSTHRUFS_MEMORY_CACHE_SIZE", value_name = "BYTES")]
+    pub memory_cache_size: Option<usize>,
+
+    /// Owner of the virtual files [default: the mounting user]
+    #[arg(long, global = true, env = "PASSTHRUFS_UID")]
+    pub uid: Option<u32>,
+
+    /// Group of the virtual files [default: the mounting user's group]
+    #[arg(long, global = true, env = "PASSTHRUFS_GID")]
+    pub gid: Option<u32>,
+
+    /// Permissions of the virtual files, in octal [default: 444]
+    #[arg(long, global = true, env = "PASSTHRUFS_MODE", value_parser = parse_mode)]
+    pub mode: Option<u32>,
+
+    /// Number of threads serving FUSE requests at once [default: 8]
+    #[arg(long, global = true, env = "PASSTHRUFS_THREADS")]
+    pub threads: Option<usize>,
+
+    /// Largest range asked of the backend in one request, in bytes; bigger reads are split
+    /// [default: 4 MiB]
+    #[arg(long, global = true, env = "PASSTHRUFS_REQUEST_SIZE", value_name = "BYTES")]
+    pub request_size: Option<u64>,
+
+    /// Number of threads fetching the pieces of split reads at once [default: 8]
+    #[arg(long, global = true, env = "PASSTHRUFS_FETCH_THREADS")]
+    pub fetch_threads: Option<usize>,
+
+    /// Most idle connections to the backend kept open for reuse [default: 32]
+    #[arg(long, global = true, env = "PASSTHRUFS_HTTP_POOL_SIZE")]
+    pub http_pool_size: Option<usize>,
+
+    /// Seconds an idle connection is kept open for reuse [default: 90]
+    #[arg(long, global = true, env = "PASSTHRUFS_HTTP_IDLE_TIMEOUT", value_name = "SECONDS")]
+    pub http_idle_timeout: Option<u64>,
+
+    /// Seconds between TCP keep-alive probes on open connections [default: 60]
+    #[arg(long, global = true, env = "PASSTHRUFS_HTTP_KEEPALIVE", value_name = "SECONDS")]
+    pub http_keepalive: Option<u64>,
+
+    /// Seconds allowed for connecting, TLS handshake included [default: 10]
+    #[arg(long, global = true, env = "PASSTHRUFS_CONNECT_TIMEOUT", value_name = "SECONDS")]
+    pub connect_timeout: Option<u64>,
+
+    /// Seconds allowed for a whole request, body included [default: 60]
+    #[arg(long, global = true, env = "PASSTHRUFS_REQUEST_TIMEOUT", value_name = "SECONDS")]
+    pub request_timeout: Option<u64>,
+
+    /// Whether to use HTTP/2 where the server offers it [default: true]
+    #[arg(long, global = true, env = "PASSTHRUFS_HTTP2", value_name = "BOOL")]
+    pub http2: Option<bool>,
+
+    /// Tries per backend request before giving up, the first included [default: 6]
+    #[arg(long, global = true, env = "PASSTHRUFS_HTTP_ATTEMPTS")]
+    pub http_attempts: Option<u32>,
+
+    /// One of off, error, warn, info, debug or trace [default: info]
+    #[arg(long, global = true, env = "PASSTHRUFS_LOG_LEVEL", value_name = "LEVEL",
+          value_parser = parse_log_level)]
+    pub log_level: Option<log::LevelFilter>,
+
+    /// Extra FUSE mount options, as for mount(8); may be repeated.
+    #[arg(short = 'o', global = true, value_name = "OPTION")]
+    #[serde(rename = "options")]
+    pub fuse_options: Vec<String>,
+}
+
+fn parse_mode(s: &str) -> Result<u32, String> {
+    match u32::from_str_radix(s, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("{:?} is not an octal file mode", s)),
+    }
+}
+
+fn parse_log_level(s: &str) -> Result<log::LevelFilter, String> {
+    s.parse().map_err(|_| format!("{:?} is not a log level", s))
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io { path: PathBuf, source: io::Error },
+    Parse { path: PathBuf, source: toml::de::Error },
+    /// The file parsed, but an option has a value that makes no sense.
+    Invalid { path: PathBuf, msg: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, source } => write!(f, "cannot read config file {:?}: {}", path, source),
+            ConfigError::Parse { path, source } => write!(f, "cannot parse config file {:?}: {}", path, source),
+            ConfigError::Invalid { path, msg } => write!(f, "config file {:?}: {}", path, msg),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Options {
+    /// Read options from the TOML config file at `path`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Options, ConfigError> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .map_err(|source| ConfigError::Io { path: path.to_owned(), source })?;
+        let options: Options = toml::from_str(&text)
+            .map_err(|source| ConfigError::Parse { path: path.to_owned(), source })?;
+        if let Some(mode) = options.mode {
+            if mode > 0o7777 {
+                let msg = format!("mode {:#o} is not a file mode", mode);
+                return Err(ConfigError::Invalid { path: path.to_owned(), msg });
+            }
+        }
+        Ok(options)
+    }
+
+    /// Fill in whatever was not given here from `file`.
+    pub fn or(self, file: Options) -> Options {
+        Options {
+            manifests: self.manifests.or(file.manifests),
+            source: self.source.or(file.source),
+            mountpoint: self.mountpoint.or(file.mountpoint),
+            chunk_dir: self.chunk_dir.or(file.chunk_dir),
+            credentials: self.credentials.or(file.credentials),
+            token_uri: self.token_uri.or(file.token_uri),
+            key_file: self.key_file.or(file.key_file),
+            cache_dir: self.cache_dir.or(file.cache_dir),
+            cache_size: self.cache_size.or(file.cache_size),
+            memory_cache_size: self.memory_cache_size.or(file.memory_cache_size),
+            uid: self.uid.or(file.uid),
+            gid: self.gid.or(file.gid),
+            mode: self.mode.or(file.mode),
+            threads: self.threads.or(file.threads),
+            request_size: self.request_size.or(file.request_size),
+            fetch_threads: self.fetch_threads.or(file.fetch_threads),
+            http_pool_size: self.http_pool_size.or(file.http_pool_size),
+            http_idle_timeout: self.http_idle_timeout.or(file.http_idle_timeout),
+            http_keepalive: self.http_keepalive.or(file.http_keepalive),
+            connect_timeout: self.connect_timeout.or(file.connect_timeout),
+            request_timeout: self.request_timeout.or(file.request_timeout),
+            http2: self.http2.or(file.http2),
+            http_attempts: self.http_attempts.or(file.http_attempts),
+            log_level: self.log_level.or(file.log_level),
+            // Later options override earlier ones, so the config file's go first.
+            fuse_options: file.fuse_options.into_iter().chain(self.fuse_options).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    /// Options from the config file `text`.
+    fn load(text: &str) -> Result<Options, ConfigError> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("passthrufs.toml");
+        fs::write(&path, text).unwrap();
+        Options::load(path)
+    }
+
+    fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from([&["passthrufs"], args, &["inspect"]].concat())
+    }
+
+    #[test]
+    fn flags_and_environment_win_over_the_file() {
+        let file = load(r#"
+            cache-dir = "/from/file"
+            uid = 1000
+            gid = 1000
+            mode = 0o440
+        "#).unwrap();
+
+        env::set_var("PASSTHRUFS_GID", "42");
+        let cli = parse(&["--cache-dir", "/from/flag"]);
+        env::remove_var("PASSTHRUFS_GID");
+        let options = cli.unwrap().options.or(file);
+
+        assert_eq!(options.cache_dir.as_deref(), Some(Path::new("/from/flag")));
+        assert_eq!(options.gid, Some(42));
+        assert_eq!(options.uid, Some(1000));
+        assert_eq!(options.mode, Some(0o440));
+        assert_eq!(options.source, None);
+    }
+
+    #[test]
+    fn mount_options_from_both_are_kept() {
+        let file = load(r#"options = ["allow_other", "ro"]"#).unwrap();
+        let options = parse(&["-o", "noatime", "-o", "ro"]).unwrap().options.or(file);
+        assert_eq!(options.fuse_options, ["allow_other", "ro", "noatime", "ro"]);
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        let e = load("cache-dir = \"/x\"\ncache-sise = 5\n").unwrap_err();
+        assert!(matches!(e, ConfigError::Parse { .. }));
+        let message = e.to_string();
+        assert!(message.contains("passthrufs.toml"), "{}", message);
+        assert!(message.contains("cache-sise"), "{}", message);
+    }
+
+    #[test]
+    fn bad_modes_are_reported() {
+        let e = load("mode = 0o10000").unwrap_err();
+        assert!(matches!(e, ConfigError::Invalid { .. }));
+        assert!(e.to_string().contains("passthrufs.toml"), "{}", e);
+
+        assert_eq!(parse(&["--mode", "640"]).unwrap().options.mode, Some(0o640));
+        for mode in ["8", "rw", "10000"] {
+            let e = parse(&["--mode", mode]).unwrap_err();
+            assert!(e.to_string().contains("is not an octal file mode"), "{}", e);
+        }
+    }
+}
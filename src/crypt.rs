@@ -0,0 +1,726 @@
+// Crypt :: Decryption of encrypted chunks.
+//
+// A Fernet chunk can only be decrypted as a whole, so the first read of one downloads and
+// decrypts it, and the plaintext is kept in memory for later reads; it is zeroed when dropped.
+// Manifests are loaded once per mount and the store holds reads to the version of a chunk first
+// seen, so the plaintext stays good for as long as the mount does.
+//
+// The key is never built in. It is read from a key file or taken as given (e.g. from the
+// environment), or derived from a passphrase with scrypt, using the parameters and salt recorded
+// in the file's manifest. Keys are resolved for every file when mounting, so that a missing or
+// wrong key stops the mount rather than failing reads later.
+//
+// To allow rotation, a key file or variable may hold several keys, newest first. Decryption tries
+// each in turn and encryption always uses the first, so after adding a new key at the front,
+// `rotate` re-encrypts Fernet chunks and re-seals AEAD chunks still under an old one, and the old
+// key can then be dropped.
+//
+// AEAD chunks are sealed in blocks with ChaCha20-Poly1305, so they can be read at random without
+// fetching or trusting anything outside the blocks needed. Each chunk has its own key, derived
+// with HKDF-SHA256 from the file's key, the chunk's salt and its ID. The nonce is the block index,
+// and each block's associated data says whether it is the last block, so blocks can't be moved
+// around within a chunk nor the chunk silently truncated. `seal_blocks` produces such a chunk.
+//
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str;
+use std::sync::{Arc, Mutex, RwLock};
+
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE};
+use chacha20poly1305::aead::{Aead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit, Nonce};
+use fernet::Fernet;
+use hkdf::Hkdf;
+use md5::{Digest, Md5};
+use sha2::Sha256;
+use zeroize::Zeroizing;
+
+use crate::error::FsError;
+use crate::manifest::{Chunk, Encoding, Kdf, Manifest, ManifestError, AEAD_BLOCK_SIZE, AEAD_TAG_SIZE};
+use crate::store::{ChunkStore, StoreResult};
+
+/// Where the keys for encrypted chunks come from.
+pub enum KeySource {
+    /// A file holding URL-safe base64 keys, one per line.
+    File(PathBuf),
+    /// URL-safe base64 keys separated by commas.
+    Literal(Zeroizing<String>),
+    /// A passphrase, stretched with the KDF named in each file's manifest.
+    Passphrase(Zeroizing<String>),
+}
+
+#[derive(Debug)]
+pub enum KeyError {
+    /// The file has encrypted chunks but no key source was configured.
+    Missing,
+    Io { path: PathBuf, source: io::Error },
+    /// A key is not a valid Fernet key.
+    Invalid,
+    /// The key source holds no keys at all.
+    Empty,
+    /// A passphrase was given, but the manifest has no KDF parameters to use it with.
+    NoKdf,
+    /// The KDF parameters in the manifest are unusable.
+    Kdf(String),
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyError::Missing => write!(f, "file has encrypted chunks, but no key was given"),
+            KeyError::Io { path, source } => write!(f, "cannot read key file {:?}: {}", path, source),
+            KeyError::Invalid => write!(f, "key is not a URL-safe base64 encoding of 32 bytes"),
+            KeyError::Empty => write!(f, "no keys given"),
+            KeyError::NoKdf => write!(f, "a passphrase was given, but the manifest has no kdf section"),
+            KeyError::Kdf(msg) => write!(f, "bad kdf parameters: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+impl KeySource {
+    /// The key source configured: the key file if one is given, otherwise keys or a passphrase
+    /// from the environment variable `PASSTHRUFS_KEY` or `PASSTHRUFS_PASSPHRASE`, looked up with
+    /// `var`.
+    pub fn configured(key_file: Option<&Path>, var: impl Fn(&str) -> Option<String>) -> Option<KeySource> {
+        if let Some(path) = key_file {
+            Some(KeySource::File(path.to_owned()))
+        } else if let Some(keys) = var("PASSTHRUFS_KEY") {
+            Some(KeySource::Literal(keys.into()))
+        } else {
+            var("PASSTHRUFS_PASSPHRASE").map(|passphrase| KeySource::Passphrase(passphrase.into()))
+        }
+    }
+
+    /// Produce the keys for a file whose manifest specifies `kdf`.
+    pub fn keys(&self, kdf: Option<&Kdf>) -> Result<Keyring, KeyError> {
+        let parse = |list: &str, separator: char| {
+            list.split(separator)
+                .map(str::trim)
+                .filter(|key| !key.is_empty())
+                .map(Key::parse)
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let keys = match self {
+            KeySource::File(path) => {
+                let text = Zeroizing::new(fs::read_to_string(path)
+                    .map_err(|source| KeyError::Io { path: path.clone(), source })?);
+                parse(&text, '\n')?
+            }
+            KeySource::Literal(list) => parse(list, ',')?,
+            KeySource::Passphrase(passphrase) => vec![derive_key(passphrase, kdf.ok_or(KeyError::NoKdf)?)?],
+        };
+        if keys.is_empty() {
+            return Err(KeyError::Empty);
+        }
+        Ok(Keyring { keys })
+    }
+}
+
+/// Resolve the keys for every file in `files` with encrypted chunks, failing with the name of
+/// the first file whose keys can't be had from `source`.
+pub fn resolve_keys(source: Option<&KeySource>, files: &BTreeMap<OsString, Manifest>)
+    -> Result<BTreeMap<OsString, Keyring>, (OsString, KeyError)>
+{
+    let mut keys = BTreeMap::new();
+    for (name, manifest) in files {
+        if manifest.first_encrypted().is_none() {
+            continue;
+        }
+        let keyring = source.ok_or(KeyError::Missing)
+            .and_then(|source| source.keys(manifest.kdf.as_ref()))
+            .map_err(|e| (name.clone(), e))?;
+        keys.insert(name.clone(), keyring);
+    }
+    Ok(keys)
+}
+
+struct Key {
+    fernet: Fernet,
+    /// The key's 32 bytes, which AEAD chunk keys are derived from.
+    raw: Zeroizing<Vec<u8>>,
+}
+
+impl Key {
+    fn parse(text: &str) -> Result<Key, KeyError> {
+        let raw = Zeroizing::new(URL_SAFE.decode(text).map_err(|_| KeyError::Invalid)?);
+        if raw.len() != 32 {
+            return Err(KeyError::Invalid);
+        }
+        Ok(Key {
+            fernet: Fernet::new(text).ok_or(KeyError::Invalid)?,
+            raw,
+        })
+    }
+
+    /// The cipher for the AEAD chunk `chunk`.
+    fn chunk_cipher(&self, chunk: &Chunk) -> StoreResult<ChaCha20Poly1305> {
+        let salt = chunk.salt.as_deref()
+            .map(|salt| STANDARD.decode(salt))
+            .transpose()
+            .map_err(|e| FsError::Decrypt(format!("chunk {}: bad salt: {}", chunk.id, e)))?;
+        let mut okm = Zeroizing::new([0u8; 32]);
+        let info = [b"passthrufs aead chunk ".as_slice(), chunk.id.as_bytes()].concat();
+        Hkdf::<Sha256>::new(salt.as_deref(), &self.raw)
+            .expand(&info, &mut *okm)
+            .map_err(|e| FsError::Decrypt(e.to_string()))?;
+        ChaCha20Poly1305::new_from_slice(&*okm).map_err(|e| FsError::Decrypt(e.to_string()))
+    }
+}
+
+/// Keys for encrypted chunks, newest first.
+pub struct Keyring {
+    keys: Vec<Key>,
+}
+
+impl Keyring {
+    /// Decrypt `token` with whichever key works, returning that key's position and the plaintext.
+    fn decrypt(&self, token: &str) -> Option<(usize, Zeroizing<Vec<u8>>)> {
+        self.keys.iter().enumerate()
+            .find_map(|(i, key)| key.fernet.decrypt(token).ok().map(|plaintext| (i, Zeroizing::new(plaintext))))
+    }
+
+    /// Encrypt with the newest key.
+    fn encrypt(&self, plaintext: &[u8]) -> String {
+        self.keys[0].fernet.encrypt(plaintext)
+    }
+
+    /// Find the first key whose cipher for the AEAD chunk `chunk` opens `block` (block number
+    /// `index`), returning that key's position and the cipher.
+    fn aead_cipher(&self, chunk: &Chunk, index: u64, block: &[u8]) -> StoreResult<(usize, ChaCha20Poly1305)> {
+        for (used, key) in self.keys.iter().enumerate() {
+            let cipher = key.chunk_cipher(chunk)?;
+            if open_block(&cipher, chunk, index, block).is_ok() {
+                return Ok((used, cipher));
+            }
+        }
+        Err(FsError::Decrypt(format!("chunk {}: block {} fails authentication with every key", chunk.id, index)))
+    }
+}
+
+fn derive_key(passphrase: &str, kdf: &Kdf) -> Result<Key, KeyError> {
+    match kdf {
+        Kdf::Scrypt { salt, log_n, r, p } => {
+            let salt = STANDARD.decode(salt).map_err(|e| KeyError::Kdf(format!("salt: {}", e)))?;
+            let params = scrypt::Params::new(*log_n, *r, *p, 32)
+                .map_err(|e| KeyError::Kdf(e.to_string()))?;
+            let mut raw = Zeroizing::new([0u8; 32]);
+            scrypt::scrypt(passphrase.as_bytes(), &salt, &params, &mut *raw)
+                .map_err(|e| KeyError::Kdf(e.to_string()))?;
+            Key::parse(&Zeroizing::new(URL_SAFE.encode(*raw)))
+        }
+    }
+}
+
+pub type Plaintext = Arc<Zeroizing<Vec<u8>>>;
+
+struct ChunkCipher {
+    /// The salt the cipher was derived with.
+    salt: Option<String>,
+    cipher: Arc<ChaCha20Poly1305>,
+}
+
+/// A decrypted Fernet chunk, once there is one, behind a lock of its own.
+type Slot = Arc<Mutex<Option<Plaintext>>>;
+
+pub struct Decryptor {
+    /// Keys for each virtual file that has encrypted chunks.
+    keys: BTreeMap<OsString, Keyring>,
+    /// Decrypted Fernet chunks.
+    headers: Mutex<HashMap<String, Slot>>,
+    /// Cipher for each AEAD chunk read so far, with the salt it was derived for.
+    ciphers: RwLock<HashMap<String, ChunkCipher>>,
+}
+
+impl Decryptor {
+    pub fn new(keys: BTreeMap<OsString, Keyring>) -> Decryptor {
+        Decryptor {
+            keys,
+            headers: Mutex::new(HashMap::new()),
+            ciphers: RwLock::new(HashMap::new()),
+        }
+    }
+
+    fn keyring(&self, file: &OsStr) -> StoreResult<&Keyring> {
+        self.keys.get(file).ok_or_else(|| FsError::Decrypt(format!("no key for {:?}", file)))
+    }
+
+    /// Return the plaintext of the Fernet chunk `chunk` of virtual file `file`, fetching and
+    /// decrypting it the first time it is asked for.
+    pub fn fernet_plaintext(&self, store: &dyn ChunkStore, file: &OsStr, chunk: &Chunk) -> StoreResult<Plaintext> {
+        let key = self.keyring(file)?;
+        let slot = Arc::clone(self.headers.lock().unwrap().entry(chunk.id.clone()).or_default());
+        // Held across the download, so that a burst of reads of a chunk that isn't decrypted yet
+        // fetches it once, without holding up reads of other chunks.
+        let mut entry = slot.lock().unwrap();
+        if let Some(plaintext) = entry.as_ref() {
+            return Ok(Arc::clone(plaintext));
+        }
+
+        let (used, plaintext) = decrypt(key, chunk, &store.read_all(&chunk.id)?)?;
+        if used > 0 {
+            warn!("chunk {} is encrypted with old key #{}; run rotate to re-encrypt it", chunk.id, used + 1);
+        }
+        let plaintext = Arc::new(plaintext);
+        *entry = Some(Arc::clone(&plaintext));
+        Ok(plaintext)
+    }
+
+    /// Return plaintext bytes `start..=end` of the AEAD chunk `chunk` of virtual file `file`,
+    /// given `sealed`, the stored bytes `chunk.stored_range(start, end)`.
+    pub fn open_range(&self, file: &OsStr, chunk: &Chunk, start: u64, end: u64, sealed: &[u8]) -> StoreResult<Zeroizing<Vec<u8>>> {
+        let (stored_start, stored_end) = chunk.stored_range(start, end);
+        if sealed.len() as u64 != stored_end - stored_start + 1 {
+            return Err(FsError::Decrypt(format!("chunk {}: expected {} sealed bytes, got {}",
+                                                chunk.id, stored_end - stored_start + 1, sealed.len())));
+        }
+        let first = start / AEAD_BLOCK_SIZE;
+        let plaintext = self.open_blocks(file, chunk, first, sealed)?;
+        let skip = (start - first * AEAD_BLOCK_SIZE) as usize;
+        Ok(Zeroizing::new(plaintext[skip..=skip + (end - start) as usize].to_vec()))
+    }
+
+    /// Open the sealed blocks `sealed` of the AEAD chunk `chunk` of virtual file `file`, the first
+    /// of which is block number `first`.
+    fn open_blocks(&self, file: &OsStr, chunk: &Chunk, first: u64, sealed: &[u8]) -> StoreResult<Zeroizing<Vec<u8>>> {
+        let blocks: Vec<&[u8]> = sealed.chunks((AEAD_BLOCK_SIZE + AEAD_TAG_SIZE) as usize).collect();
+        let cipher = match blocks.first() {
+            Some(block) => self.aead_cipher(file, chunk, first, block)?,
+            None => return Ok(Zeroizing::new(vec![])),
+        };
+        let mut plaintext = Zeroizing::new(Vec::with_capacity(blocks.len() * AEAD_BLOCK_SIZE as usize));
+        for (index, block) in (first..).zip(blocks) {
+            plaintext.extend_from_slice(&open_block(&cipher, chunk, index, block)?);
+        }
+        Ok(plaintext)
+    }
+
+    /// Find the cipher for `chunk`: the one used before, or else the first whose key opens
+    /// `block` (block number `index`).
+    fn aead_cipher(&self, file: &OsStr, chunk: &Chunk, index: u64, block: &[u8]) -> StoreResult<Arc<ChaCha20Poly1305>> {
+        if let Some(known) = self.ciphers.read().unwrap().get(&chunk.id) {
+            if known.salt == chunk.salt {
+                return Ok(Arc::clone(&known.cipher));
+            }
+        }
+        let (used, cipher) = self.keyring(file)?.aead_cipher(chunk, index, block)?;
+        if used > 0 {
+            warn!("chunk {} is sealed with old key #{}; run rotate to re-seal it", chunk.id, used + 1);
+        }
+        let cipher = Arc::new(cipher);
+        self.ciphers.write().unwrap().insert(chunk.id.clone(), ChunkCipher {
+            salt: chunk.salt.clone(),
+            cipher: Arc::clone(&cipher),
+        });
+        Ok(cipher)
+    }
+}
+
+/// The nonce and associated data of block number `index` of a chunk of `blocks` sealed blocks.
+fn block_nonce(index: u64, blocks: u64) -> ([u8; 12], [u8; 1]) {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&index.to_be_bytes());
+    (nonce, [u8::from(index + 1 == blocks)])
+}
+
+/// Verify and decrypt block number `index` of an AEAD chunk.
+fn open_block(cipher: &ChaCha20Poly1305, chunk: &Chunk, index: u64, block: &[u8]) -> StoreResult<Zeroizing<Vec<u8>>> {
+    let blocks = chunk.aead_blocks();
+    if index >= blocks {
+        return Err(FsError::Decrypt(format!("chunk {}: block {} is past the end", chunk.id, index)));
+    }
+    let (nonce, last) = block_nonce(index, blocks);
+    cipher.decrypt(Nonce::from_slice(&nonce), Payload { msg: block, aad: &last })
+        .map(Zeroizing::new)
+        .map_err(|_| FsError::Decrypt(format!("chunk {}: block {} fails authentication", chunk.id, index)))
+}
+
+/// Seal `plaintext` with the newest key in `keys` as the stored object of the AEAD chunk `chunk`,
+/// whose size and salt must already be the ones it will be listed with in the manifest.
+pub fn seal_blocks(keys: &Keyring, chunk: &Chunk, plaintext: &[u8]) -> StoreResult<Vec<u8>> {
+    if plaintext.len() as u64 != chunk.size {
+        return Err(ManifestError::SizeMismatch {
+            id: chunk.id.clone(),
+            expected: chunk.size,
+            actual: plaintext.len() as u64,
+        }.into());
+    }
+    let cipher = keys.keys[0].chunk_cipher(chunk)?;
+    let blocks = chunk.aead_blocks();
+    let mut sealed = Vec::with_capacity(chunk.stored_size() as usize);
+    for (index, block) in (0..).zip(plaintext.chunks(AEAD_BLOCK_SIZE as usize)) {
+        let (nonce, last) = block_nonce(index, blocks);
+        let block = cipher.encrypt(Nonce::from_slice(&nonce), Payload { msg: block, aad: &last })
+            .map_err(|_| FsError::Decrypt(format!("chunk {}: cannot seal block {}", chunk.id, index)))?;
+        sealed.extend_from_slice(&block);
+    }
+    Ok(sealed)
+}
+
+/// Open every block of the AEAD chunk `chunk`, stored as `sealed`, returning the position of the
+/// key which worked and the plaintext.
+fn open_all(keys: &Keyring, chunk: &Chunk, sealed: &[u8]) -> StoreResult<(usize, Zeroizing<Vec<u8>>)> {
+    if sealed.len() as u64 != chunk.stored_size() {
+        return Err(ManifestError::SizeMismatch {
+            id: chunk.id.clone(),
+            expected: chunk.stored_size(),
+            actual: sealed.len() as u64,
+        }.into());
+    }
+    let blocks: Vec<&[u8]> = sealed.chunks((AEAD_BLOCK_SIZE + AEAD_TAG_SIZE) as usize).collect();
+    let (used, cipher) = keys.aead_cipher(chunk, 0, blocks[0])?;
+    let mut plaintext = Zeroizing::new(Vec::with_capacity(chunk.size as usize));
+    for (index, block) in (0..).zip(blocks) {
+        plaintext.extend_from_slice(&open_block(&cipher, chunk, index, block)?);
+    }
+    Ok((used, plaintext))
+}
+
+/// Re-encrypt the Fernet or AEAD chunk `chunk` with the newest key in `keys`, if it isn't
+/// already.
+///
+/// Returns the new MD5 checksum of the stored object, to go in the manifest. AEAD chunks keep
+/// their salt, so the manifest still describes the chunk correctly if it is not saved.
+pub fn rotate(store: &dyn ChunkStore, keys: &Keyring, chunk: &Chunk) -> StoreResult<Option<String>> {
+    let stored = store.read_all(&chunk.id)?;
+    let (used, data) = match chunk.encoding {
+        Encoding::Fernet => {
+            let (used, plaintext) = decrypt(keys, chunk, &stored)?;
+            (used, keys.encrypt(&plaintext).into_bytes())
+        }
+        Encoding::Aead => {
+            let (used, plaintext) = open_all(keys, chunk, &stored)?;
+            (used, seal_blocks(keys, chunk, &plaintext)?)
+        }
+        Encoding::Plain => return Ok(None),
+    };
+    if used == 0 {
+        info!("chunk {} already uses the current key", chunk.id);
+        return Ok(None);
+    }
+    store.write(&chunk.id, &data)?;
+    info!("chunk {}: re-encrypted from key #{} to the current key", chunk.id, used + 1);
+    let checksum = Md5::digest(&data).iter().map(|b| format!("{:02x}", b)).collect();
+    Ok(Some(checksum))
+}
+
+/// Decrypt a Fernet chunk, returning the position of the key which worked and the plaintext.
+fn decrypt(keys: &Keyring, chunk: &Chunk, data: &[u8]) -> StoreResult<(usize, Zeroizing<Vec<u8>>)> {
+    let token = str::from_utf8(data)
+        .map_err(|_| FsError::Decrypt(format!("chunk {} is not a fernet token", chunk.id)))?;
+    let (used, plaintext) = keys.decrypt(token)
+        .ok_or_else(|| FsError::Decrypt(format!("chunk {}: bad token or no matching key", chunk.id)))?;
+    if plaintext.len() as u64 != chunk.size {
+        return Err(ManifestError::SizeMismatch {
+            id: chunk.id.clone(),
+            expected: chunk.size,
+            actual: plaintext.len() as u64,
+        }.into());
+    }
+    Ok((used, plaintext))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{CountingStore, LocalStore};
+    use rand::Rng;
+    use std::path::Path;
+
+    /// Three full blocks and a short one.
+    const SIZE: u64 = 3 * AEAD_BLOCK_SIZE + 1000;
+
+    fn keyring(keys: &[&str]) -> Keyring {
+        KeySource::Literal(keys.join(",").into()).keys(None).unwrap()
+    }
+
+    fn aead_chunk(size: u64) -> Chunk {
+        serde_json::from_value(serde_json::json!({
+            "id": "sealed",
+            "start": 0,
+            "end": size - 1,
+            "size": size,
+            "encoding": "aead",
+            "salt": STANDARD.encode(b"0123456789abcdef"),
+        })).unwrap()
+    }
+
+    /// A chunk sealed with `keys` into a local directory, with the plaintext it holds.
+    fn sealed(dir: &Path, keys: &Keyring) -> (LocalStore, Chunk, Vec<u8>) {
+        let plaintext: Vec<u8> = (0..SIZE).map(|i| (i * 13 % 251) as u8).collect();
+        let chunk = aead_chunk(SIZE);
+        let sealed = seal_blocks(keys, &chunk, &plaintext).unwrap();
+        assert_eq!(sealed.len() as u64, chunk.stored_size());
+        fs::write(dir.join(&chunk.id), sealed).unwrap();
+        (LocalStore::new(dir), chunk, plaintext)
+    }
+
+    fn with_keys(keys: Keyring) -> Decryptor {
+        Decryptor::new(BTreeMap::from([(OsString::from("f"), keys)]))
+    }
+
+    /// Read plaintext bytes `start..=end` of `chunk` as the filesystem does.
+    fn read(decryptor: &Decryptor, store: &LocalStore, chunk: &Chunk, start: u64, end: u64) -> StoreResult<Vec<u8>> {
+        let (stored_start, stored_end) = chunk.stored_range(start, end);
+        let sealed = store.read_range(&chunk.id, stored_start, stored_end)?;
+        Ok(decryptor.open_range(OsStr::new("f"), chunk, start, end, &sealed)?.to_vec())
+    }
+
+    fn sealed_block(index: u64) -> std::ops::Range<usize> {
+        let sealed = (AEAD_BLOCK_SIZE + AEAD_TAG_SIZE) as usize;
+        index as usize * sealed..(index as usize + 1) * sealed
+    }
+
+    #[test]
+    fn random_reads_across_blocks() {
+        let key = fernet::Fernet::generate_key();
+        let dir = tempfile::tempdir().unwrap();
+        let (store, chunk, plaintext) = sealed(dir.path(), &keyring(&[&key]));
+        let decryptor = with_keys(keyring(&[&key]));
+
+        assert_eq!(read(&decryptor, &store, &chunk, 0, SIZE - 1).unwrap(), plaintext);
+        for boundary in [AEAD_BLOCK_SIZE, 2 * AEAD_BLOCK_SIZE, 3 * AEAD_BLOCK_SIZE] {
+            for (start, end) in [(boundary - 1, boundary), (boundary - 10, boundary + 10), (boundary, boundary)] {
+                assert_eq!(read(&decryptor, &store, &chunk, start, end).unwrap(),
+                           &plaintext[start as usize..=end as usize], "{}..={}", start, end);
+            }
+        }
+        let mut rng = rand::thread_rng();
+        for _ in 0..100 {
+            let start = rng.gen_range(0..SIZE);
+            let end = rng.gen_range(start..SIZE.min(start + 2 * AEAD_BLOCK_SIZE));
+            assert_eq!(read(&decryptor, &store, &chunk, start, end).unwrap(),
+                       &plaintext[start as usize..=end as usize], "{}..={}", start, end);
+        }
+    }
+
+    #[test]
+    fn tampered_block_fails_authentication() {
+        let key = fernet::Fernet::generate_key();
+        let dir = tempfile::tempdir().unwrap();
+        let (store, chunk, plaintext) = sealed(dir.path(), &keyring(&[&key]));
+        let path = dir.path().join(&chunk.id);
+        let mut data = fs::read(&path).unwrap();
+        data[sealed_block(1).start + 100] ^= 1;
+        fs::write(&path, data).unwrap();
+
+        let decryptor = with_keys(keyring(&[&key]));
+        assert_eq!(read(&decryptor, &store, &chunk, 0, 99).unwrap(), &plaintext[..100]);
+        let e = read(&decryptor, &store, &chunk, AEAD_BLOCK_SIZE + 5, AEAD_BLOCK_SIZE + 10).unwrap_err();
+        assert!(matches!(e, FsError::Decrypt(_)), "{}", e);
+        assert!(read(&decryptor, &store, &chunk, 0, SIZE - 1).is_err());
+    }
+
+    #[test]
+    fn swapped_blocks_fail_authentication() {
+        let key = fernet::Fernet::generate_key();
+        let dir = tempfile::tempdir().unwrap();
+        let (store, chunk, _) = sealed(dir.path(), &keyring(&[&key]));
+        let path = dir.path().join(&chunk.id);
+        let mut data = fs::read(&path).unwrap();
+        let second = data[sealed_block(1)].to_vec();
+        data.copy_within(sealed_block(0), sealed_block(1).start);
+        data[sealed_block(0)].copy_from_slice(&second);
+        fs::write(&path, data).unwrap();
+
+        let decryptor = with_keys(keyring(&[&key]));
+        assert!(matches!(read(&decryptor, &store, &chunk, 0, 10), Err(FsError::Decrypt(_))));
+        assert!(matches!(read(&decryptor, &store, &chunk, AEAD_BLOCK_SIZE, AEAD_BLOCK_SIZE + 10),
+                         Err(FsError::Decrypt(_))));
+    }
+
+    #[test]
+    fn truncated_chunk_fails_authentication() {
+        let key = fernet::Fernet::generate_key();
+        let dir = tempfile::tempdir().unwrap();
+        let (store, chunk, _) = sealed(dir.path(), &keyring(&[&key]));
+        let path = dir.path().join(&chunk.id);
+        let data = fs::read(&path).unwrap();
+        let opener = with_keys(keyring(&[&key]));
+
+        // Cut short within the last block.
+        fs::write(&path, &data[..data.len() - 10]).unwrap();
+        assert!(matches!(read(&opener, &store, &chunk, SIZE - 5, SIZE - 1), Err(FsError::Decrypt(_))));
+
+        // Cut at a block boundary, with the chunk described as ending there: the block that is
+        // now last was not sealed as the last one.
+        fs::write(&path, &data[..sealed_block(3).start]).unwrap();
+        let shorter = aead_chunk(3 * AEAD_BLOCK_SIZE);
+        let (fresh, store) = (with_keys(keyring(&[&key])), LocalStore::new(dir.path()));
+        assert!(matches!(read(&fresh, &store, &shorter, 3 * AEAD_BLOCK_SIZE - 5, 3 * AEAD_BLOCK_SIZE - 1),
+                         Err(FsError::Decrypt(_))));
+    }
+
+    #[test]
+    fn chunk_sealed_with_an_older_key() {
+        let (new, old) = (fernet::Fernet::generate_key(), fernet::Fernet::generate_key());
+        let dir = tempfile::tempdir().unwrap();
+        let (store, chunk, plaintext) = sealed(dir.path(), &keyring(&[&old]));
+
+        let decryptor = with_keys(keyring(&[&new, &old]));
+        assert_eq!(read(&decryptor, &store, &chunk, AEAD_BLOCK_SIZE - 3, SIZE - 1).unwrap(),
+                   &plaintext[AEAD_BLOCK_SIZE as usize - 3..]);
+        let only_new = with_keys(keyring(&[&new]));
+        assert!(matches!(read(&only_new, &store, &chunk, 0, 10), Err(FsError::Decrypt(_))));
+    }
+
+    #[test]
+    fn rotate_reseals_aead_chunks() {
+        let (new, old) = (fernet::Fernet::generate_key(), fernet::Fernet::generate_key());
+        let dir = tempfile::tempdir().unwrap();
+        let (store, chunk, plaintext) = sealed(dir.path(), &keyring(&[&old]));
+
+        let checksum = rotate(&store, &keyring(&[&new, &old]), &chunk).unwrap().unwrap();
+        let stored = fs::read(dir.path().join(&chunk.id)).unwrap();
+        assert_eq!(checksum, Md5::digest(&stored).iter().map(|b| format!("{:02x}", b)).collect::<String>());
+        assert_eq!(read(&with_keys(keyring(&[&new])), &store, &chunk, 0, SIZE - 1).unwrap(), plaintext);
+        assert_eq!(rotate(&store, &keyring(&[&new, &old]), &chunk).unwrap(), None);
+    }
+
+    #[test]
+    fn fernet_chunk_is_downloaded_once() {
+        let key = fernet::Fernet::generate_key();
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("header"), keyring(&[&key]).encrypt(b"header bytes")).unwrap();
+        let chunk: Chunk = serde_json::from_value(serde_json::json!({
+            "id": "header", "start": 0, "end": 11, "size": 12, "encoding": "fernet",
+        })).unwrap();
+        let store = CountingStore::new(dir.path());
+        let decryptor = with_keys(keyring(&[&key]));
+
+        for _ in 0..2 {
+            let plaintext = decryptor.fernet_plaintext(&store, OsStr::new("f"), &chunk).unwrap();
+            assert_eq!(plaintext.as_slice(), b"header bytes");
+        }
+        assert_eq!(store.reads(), 1);
+    }
+
+    #[test]
+    fn rotate_reencrypts_fernet_chunks() {
+        let (new, old) = (fernet::Fernet::generate_key(), fernet::Fernet::generate_key());
+        let dir = tempfile::tempdir().unwrap();
+        let chunks = dir.path().join("chunks");
+        fs::create_dir(&chunks).unwrap();
+        fs::write(chunks.join("header"), keyring(&[&old]).encrypt(b"header bytes")).unwrap();
+        let path = dir.path().join("f.json");
+        fs::write(&path, r#"{"version": 1, "chunks": [
+            {"id": "header", "start": 0, "end": 11, "size": 12, "encoding": "fernet"}
+        ]}"#).unwrap();
+
+        // As the rotate command does it: re-encrypt, then record the new checksum.
+        let store = LocalStore::new(&chunks);
+        let mut manifest = crate::manifest::Manifest::load(&path).unwrap();
+        let checksum = rotate(&store, &keyring(&[&new, &old]), &manifest.chunks[0]).unwrap().unwrap();
+        manifest.chunks[0].checksum = Some(checksum.clone());
+        manifest.save(&path).unwrap();
+
+        let stored = fs::read(chunks.join("header")).unwrap();
+        assert_eq!(checksum, Md5::digest(&stored).iter().map(|b| format!("{:02x}", b)).collect::<String>());
+        let manifest = crate::manifest::Manifest::load(&path).unwrap();
+        assert_eq!(manifest.chunks[0].checksum.as_deref(), Some(checksum.as_str()));
+        let plaintext = with_keys(keyring(&[&new]))
+            .fernet_plaintext(&LocalStore::new(&chunks), OsStr::new("f"), &manifest.chunks[0]).unwrap();
+        assert_eq!(plaintext.as_slice(), b"header bytes");
+        assert_eq!(rotate(&store, &keyring(&[&new, &old]), &manifest.chunks[0]).unwrap(), None);
+    }
+
+    fn scrypt(salt: &str) -> Kdf {
+        Kdf::Scrypt { salt: STANDARD.encode(salt), log_n: 4, r: 8, p: 1 }
+    }
+
+    /// Manifests for a file with an encrypted chunk, stretching passphrases with `kdf`, and a
+    /// plain file.
+    fn files(kdf: Option<Kdf>) -> BTreeMap<OsString, Manifest> {
+        let manifest = |encoding: &str| -> Manifest {
+            serde_json::from_value(serde_json::json!({
+                "version": 1,
+                "kdf": kdf,
+                "chunks": [{"id": "c", "start": 0, "end": 9, "size": 10, "encoding": encoding}],
+            })).unwrap()
+        };
+        BTreeMap::from([
+            (OsString::from("encrypted"), manifest("fernet")),
+            (OsString::from("plain"), manifest("plain")),
+        ])
+    }
+
+    fn key_error(source: Option<&KeySource>, kdf: Option<Kdf>) -> KeyError {
+        let (name, e) = resolve_keys(source, &files(kdf)).err().unwrap();
+        assert_eq!(name, "encrypted");
+        e
+    }
+
+    #[test]
+    fn key_source_comes_from_a_file_or_the_environment() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| vars.iter().find(|(var, _)| *var == name).map(|(_, value)| value.to_string())
+        };
+        let both = env(&[("PASSTHRUFS_KEY", "k"), ("PASSTHRUFS_PASSPHRASE", "p")]);
+        assert!(matches!(KeySource::configured(Some(Path::new("keys")), both), Some(KeySource::File(_))));
+        assert!(matches!(KeySource::configured(None, both), Some(KeySource::Literal(keys)) if keys.as_str() == "k"));
+        assert!(matches!(KeySource::configured(None, env(&[("PASSTHRUFS_PASSPHRASE", "p")])),
+                         Some(KeySource::Passphrase(p)) if p.as_str() == "p"));
+        assert!(KeySource::configured(None, env(&[])).is_none());
+    }
+
+    #[test]
+    fn key_file_holds_keys_one_per_line() {
+        let (new, old) = (fernet::Fernet::generate_key(), fernet::Fernet::generate_key());
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys");
+        fs::write(&path, format!("{}\n\n  {}  \n", new, old)).unwrap();
+        let keys = resolve_keys(Some(&KeySource::File(path)), &files(None)).unwrap();
+
+        // Only files with encrypted chunks need keys.
+        assert_eq!(keys.keys().collect::<Vec<_>>(), ["encrypted"]);
+        let keyring = &keys[OsStr::new("encrypted")];
+        assert_eq!(keyring.keys.len(), 2);
+        let token = keyring.encrypt(b"secret");
+        assert_eq!(keyring.decrypt(&token).unwrap().0, 0);
+        assert!(fernet::Fernet::new(&new).unwrap().decrypt(&token).is_ok());
+        let old_token = fernet::Fernet::new(&old).unwrap().encrypt(b"secret");
+        assert_eq!(keyring.decrypt(&old_token).unwrap().0, 1);
+    }
+
+    #[test]
+    fn passphrase_is_stretched_with_the_manifest_kdf() {
+        let passphrase = |p: &str| KeySource::Passphrase(p.to_owned().into());
+        let keyring = |p: &str, salt: &str| passphrase(p).keys(Some(&scrypt(salt))).unwrap();
+
+        let token = keyring("open sesame", "salt one").encrypt(b"secret");
+        assert!(keyring("open sesame", "salt one").decrypt(&token).is_some());
+        assert!(keyring("open sesame", "salt two").decrypt(&token).is_none());
+        assert!(keyring("open barley", "salt one").decrypt(&token).is_none());
+    }
+
+    #[test]
+    fn key_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = KeySource::File(dir.path().join("missing"));
+        assert!(matches!(key_error(Some(&missing), None), KeyError::Io { .. }));
+        assert!(matches!(key_error(None, None), KeyError::Missing));
+        let passphrase = KeySource::Passphrase(String::from("open sesame").into());
+        assert!(matches!(key_error(Some(&passphrase), None), KeyError::NoKdf));
+
+        let bad_salt = Kdf::Scrypt { salt: "not base64!".into(), log_n: 4, r: 8, p: 1 };
+        assert!(matches!(key_error(Some(&passphrase), Some(bad_salt)), KeyError::Kdf(_)));
+        let bad_params = Kdf::Scrypt { salt: STANDARD.encode("salt"), log_n: 4, r: 0, p: 1 };
+        assert!(matches!(key_error(Some(&passphrase), Some(bad_params)), KeyError::Kdf(_)));
+
+        let literal = |keys: &str| KeySource::Literal(keys.to_owned().into());
+        assert!(matches!(key_error(Some(&literal("not a key")), None), KeyError::Invalid));
+        // Valid base64, but 16 bytes.
+        assert!(matches!(key_error(Some(&literal(&URL_SAFE.encode([0; 16]))), None), KeyError::Invalid));
+        assert!(matches!(key_error(Some(&literal(" , ")), None), KeyError::Empty));
+        let blank = dir.path().join("blank");
+        fs::write(&blank, "\n\n").unwrap();
+        assert!(matches!(key_error(Some(&KeySource::File(blank)), None), KeyError::Empty));
+    }
+}
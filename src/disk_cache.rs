@@ -0,0 +1,645 @@
+// DiskCache :: Persistent on-disk cache of chunk blocks.
+//
+// Each cached chunk has a sparse data file in the cache directory, holding its blocks at their
+// natural offsets, and a bitmap of which blocks are present. The bitmaps live in `index.json`,
+// which is reloaded at startup so the cache survives remounts. Blocks are evicted least recently
+// used first once the cache is over its size cap.
+//
+// The index also records which version of each chunk (as the backend describes it) the blocks
+// came from. The first time a chunk is used after opening, its recorded version is checked against
+// the backend's, and its blocks are dropped if the chunk was replaced in the meantime.
+//
+// Crash safety rests on ordering: a block's data is written and synced before its bit is set,
+// and the index is only ever replaced atomically (write to a temporary file, sync, rename). The
+// index on disk therefore never claims a block whose data might be torn. Evictions clear bits and
+// persist the index before the data is discarded, so a stale index can't point at a hole either.
+//
+// Once the cache is full, it is evicted down to a low-water mark in one go rather than a block at
+// a time, so that the index isn't rewritten for every block stored.
+//
+// Block data is read and written, and the index saved, without holding the lock, so that one
+// thread waiting on the disk doesn't hold up the rest. A read that raced with an eviction from
+// the same chunk is thrown away. Only one thread writes a given block at a time, a block being
+// evicted isn't stored again until its data is gone, and the data file of a chunk with a write in
+// progress is never removed.
+//
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::libc_extras::libc;
+use crate::store::ChunkMeta;
+
+const INDEX_VERSION: u32 = 1;
+const INDEX_FILE: &str = "index.json";
+
+/// The index is rewritten after this many new blocks even if nothing else forces it.
+const PERSIST_EVERY: usize = 64;
+
+/// Eviction frees enough to bring the cache down to this fraction of its capacity, in eighths.
+const LOW_WATER_EIGHTHS: u64 = 7;
+
+#[derive(Serialize, Deserialize)]
+struct Index {
+    version: u32,
+    block_size: u64,
+    chunks: BTreeMap<String, ChunkEntry>,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct ChunkEntry {
+    /// Bit `i` is set if block `i` is present and complete in the data file.
+    bitmap: Vec<u64>,
+    /// Use counter when any block of this chunk was last read or written.
+    last_use: u64,
+    /// Eviction epoch when blocks of this chunk last went, or when it was added.
+    #[serde(skip)]
+    evicted: u64,
+    /// The version of the chunk the blocks are from.
+    #[serde(default)]
+    meta: Option<ChunkMeta>,
+}
+
+impl ChunkEntry {
+    fn has(&self, index: u64) -> bool {
+        let word = (index / 64) as usize;
+        self.bitmap.get(word).is_some_and(|w| w & (1 << (index % 64)) != 0)
+    }
+
+    fn set(&mut self, index: u64) {
+        let word = (index / 64) as usize;
+        if self.bitmap.len() <= word {
+            self.bitmap.resize(word + 1, 0);
+        }
+        self.bitmap[word] |= 1 << (index % 64);
+    }
+
+    fn clear(&mut self, index: u64) {
+        if let Some(w) = self.bitmap.get_mut((index / 64) as usize) {
+            *w &= !(1 << (index % 64));
+        }
+    }
+
+    fn blocks(&self) -> impl Iterator<Item = u64> + '_ {
+        (0..self.bitmap.len() as u64 * 64).filter(move |&i| self.has(i))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.bitmap.iter().all(|&w| w == 0)
+    }
+}
+
+pub struct DiskCache {
+    dir: PathBuf,
+    block_size: u64,
+    capacity: u64,
+    inner: Mutex<State>,
+    /// Held while saving the index, so that saves land in the order their snapshots were taken.
+    persisting: Mutex<()>,
+}
+
+struct State {
+    chunks: BTreeMap<String, ChunkEntry>,
+    /// Present blocks by last use, oldest first.
+    by_use: BTreeMap<u64, (String, u64)>,
+    /// Reverse of `by_use`.
+    last_use: HashMap<(String, u64), u64>,
+    tick: u64,
+    /// Bytes of block data held, counting every block as a full block.
+    bytes: u64,
+    unsaved: usize,
+    /// Bumped whenever blocks are evicted.
+    epoch: u64,
+    /// Blocks being written.
+    writing: HashSet<(String, u64)>,
+    /// Evicted blocks whose data hasn't been discarded yet.
+    evicting: HashSet<(String, u64)>,
+    /// Evicted chunks whose data file hasn't been removed yet.
+    removing: HashSet<String>,
+    /// The current version of each chunk checked since opening.
+    versions: HashMap<String, ChunkMeta>,
+}
+
+/// Blocks taken out of the index, whose data is still to be discarded.
+#[derive(Default)]
+struct Evicted {
+    blocks: Vec<(String, u64)>,
+    /// Chunks left with no blocks, whose data files go entirely.
+    chunks: Vec<String>,
+}
+
+impl State {
+    fn touch(&mut self, chunk_id: &str, index: u64) {
+        self.tick += 1;
+        let tick = self.tick;
+        let key = (chunk_id.to_owned(), index);
+        if let Some(old) = self.last_use.insert(key.clone(), tick) {
+            self.by_use.remove(&old);
+        }
+        self.by_use.insert(tick, key);
+        if let Some(entry) = self.chunks.get_mut(chunk_id) {
+            entry.last_use = tick;
+        }
+    }
+}
+
+/// File name for a chunk's data; IDs that aren't plain alphanumerics are hex-encoded.
+fn data_file_name(chunk_id: &str) -> String {
+    if !chunk_id.is_empty() && chunk_id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_') {
+        format!("{}.data", chunk_id)
+    } else {
+        let hex: String = chunk_id.bytes().map(|b| format!("{:02x}", b)).collect();
+        format!("x{}.data", hex)
+    }
+}
+
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+/// Release the disk space behind `len` bytes at `offset`, leaving a hole.
+#[cfg(target_os = "linux")]
+fn punch_hole(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+    if -1 == unsafe { libc::fallocate(file.as_raw_fd(), mode, offset as libc::off_t, len as libc::off_t) } {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn punch_hole(_file: &File, _offset: u64, _len: u64) -> io::Result<()> {
+    // No portable way to do this; the space is reclaimed when the whole chunk is evicted.
+    Ok(())
+}
+
+impl DiskCache {
+    /// Open (creating if needed) the cache in `dir`, reloading whatever a previous run left.
+    ///
+    /// An index written with a different block size, or one that can't be read, is discarded
+    /// along with all cached data.
+    pub fn open(dir: impl Into<PathBuf>, block_size: u64, capacity: u64) -> io::Result<DiskCache> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+
+        let index = match fs::read(dir.join(INDEX_FILE)) {
+            Ok(bytes) => match serde_json::from_slice::<Index>(&bytes) {
+                Ok(index) if index.version == INDEX_VERSION && index.block_size == block_size => Some(index),
+                Ok(_) => {
+                    warn!("disk cache {:?}: index is for a different layout; discarding", dir);
+                    None
+                }
+                Err(e) => {
+                    warn!("disk cache {:?}: unreadable index ({}); discarding", dir, e);
+                    None
+                }
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+
+        let mut chunks = index.map(|i| i.chunks).unwrap_or_default();
+        // Drop entries whose data file went missing, and data files nothing refers to.
+        chunks.retain(|id, _| dir.join(data_file_name(id)).is_file());
+        let known: Vec<String> = chunks.keys().map(|id| data_file_name(id)).collect();
+        for entry in fs::read_dir(&dir)? {
+            let name = entry?.file_name();
+            let name = name.to_string_lossy();
+            if name.ends_with(".data") && !known.iter().any(|k| *k == name) {
+                fs::remove_file(dir.join(&*name))?;
+            }
+        }
+
+        let mut state = State {
+            chunks: BTreeMap::new(),
+            by_use: BTreeMap::new(),
+            last_use: HashMap::new(),
+            tick: 0,
+            bytes: 0,
+            unsaved: 0,
+            epoch: 0,
+            writing: HashSet::new(),
+            evicting: HashSet::new(),
+            removing: HashSet::new(),
+            versions: HashMap::new(),
+        };
+        // Only chunk-level recency is persisted, so replay chunks oldest first.
+        let mut order: Vec<(String, ChunkEntry)> = chunks.into_iter().collect();
+        order.sort_by_key(|(_, entry)| entry.last_use);
+        for (id, entry) in order {
+            let blocks: Vec<u64> = entry.blocks().collect();
+            state.chunks.insert(id.clone(), entry);
+            for index in blocks {
+                state.touch(&id, index);
+                state.bytes += block_size;
+            }
+        }
+
+        info!("disk cache {:?}: {} chunks, {} bytes", dir, state.chunks.len(), state.bytes);
+        let cache = DiskCache {
+            dir,
+            block_size,
+            capacity,
+            inner: Mutex::new(state),
+            persisting: Mutex::new(()),
+        };
+        cache.persist()?;
+        Ok(cache)
+    }
+
+    /// Read block `index` of `chunk_id`, expected to be `len` bytes long, if it is cached.
+    pub fn get(&self, chunk_id: &str, index: u64, len: usize) -> Option<Vec<u8>> {
+        let epoch = {
+            let state = self.inner.lock().unwrap();
+            if !state.chunks.get(chunk_id)?.has(index) {
+                return None;
+            }
+            state.epoch
+        };
+
+        let mut buf = vec![0; len];
+        let path = self.dir.join(data_file_name(chunk_id));
+        if let Err(e) = File::open(&path).and_then(|f| f.read_exact_at(&mut buf, index * self.block_size)) {
+            error!("disk cache: reading {:?} block {}: {}", path, index, e);
+            return None;
+        }
+
+        // If blocks of this chunk were evicted meanwhile, this one may have been punched out and
+        // perhaps stored again while it was being read, so what was read can't be trusted.
+        let mut state = self.inner.lock().unwrap();
+        let entry = state.chunks.get(chunk_id)?;
+        if entry.evicted > epoch || !entry.has(index) {
+            return None;
+        }
+        state.touch(chunk_id, index);
+        Some(buf)
+    }
+
+    /// Store block `index` of `chunk_id`. Failures are logged and otherwise ignored.
+    pub fn insert(&self, chunk_id: &str, index: u64, data: &[u8]) {
+        let key = (chunk_id.to_owned(), index);
+        let evicted = {
+            let mut state = self.inner.lock().unwrap();
+            if state.chunks.get(chunk_id).is_some_and(|c| c.has(index)) {
+                state.touch(chunk_id, index);
+                return;
+            }
+            if state.writing.contains(&key) || state.evicting.contains(&key) || state.removing.contains(chunk_id) {
+                // Another thread is storing or discarding the same block.
+                return;
+            }
+            // Make room first, so the new block isn't chosen for eviction.
+            let evicted = if state.bytes + self.block_size > self.capacity {
+                self.evict(&mut state)
+            } else {
+                Evicted::default()
+            };
+            state.bytes += self.block_size;
+            state.writing.insert(key.clone());
+            evicted
+        };
+
+        if let Err(e) = self.discard(evicted) {
+            error!("disk cache: evicting: {}", e);
+        }
+        let written = self.write_block(chunk_id, index, data);
+
+        let mut state = self.inner.lock().unwrap();
+        state.writing.remove(&key);
+        if let Err(e) = written {
+            error!("disk cache: storing {} block {}: {}", chunk_id, index, e);
+            state.bytes -= self.block_size;
+            return;
+        }
+
+        // Only now that the data is durable may the block be marked present.
+        let epoch = state.epoch;
+        let meta = state.versions.get(chunk_id).cloned();
+        state.chunks.entry(chunk_id.to_owned())
+            .or_insert_with(|| ChunkEntry { evicted: epoch, meta, ..ChunkEntry::default() })
+            .set(index);
+        state.touch(chunk_id, index);
+        state.unsaved += 1;
+        if state.unsaved >= PERSIST_EVERY {
+            drop(state);
+            if let Err(e) = self.persist() {
+                error!("disk cache: saving index: {}", e);
+            }
+        }
+    }
+
+    /// Whether `chunk_id` has been checked by `check_version` since opening.
+    pub fn is_checked(&self, chunk_id: &str) -> bool {
+        self.inner.lock().unwrap().versions.contains_key(chunk_id)
+    }
+
+    /// Make sure the blocks cached for `chunk_id` came from version `current` of the chunk,
+    /// dropping them if not, and store later ones as coming from it.
+    pub fn check_version(&self, chunk_id: &str, current: &ChunkMeta) {
+        let evicted = {
+            let mut state = self.inner.lock().unwrap();
+            if state.versions.contains_key(chunk_id) {
+                return;
+            }
+            state.versions.insert(chunk_id.to_owned(), current.clone());
+            let stale = match state.chunks.get_mut(chunk_id) {
+                None => return,
+                Some(entry) if entry.meta.as_ref().is_some_and(|meta| meta.same_version(current)) => return,
+                Some(entry) => {
+                    warn!("disk cache: chunk {} changed on the backend since it was cached: was {:?}, now {:?}",
+                          chunk_id, entry.meta, current);
+                    entry.meta = Some(current.clone());
+                    entry.blocks().collect::<Vec<_>>()
+                }
+            };
+            let mut victims = vec![];
+            for index in stale {
+                let key = (chunk_id.to_owned(), index);
+                if let Some(tick) = state.last_use.remove(&key) {
+                    state.by_use.remove(&tick);
+                }
+                state.bytes -= self.block_size;
+                victims.push(key);
+            }
+            self.take_out(&mut state, victims)
+        };
+        if let Err(e) = self.discard(evicted) {
+            error!("disk cache: dropping chunk {}: {}", chunk_id, e);
+        }
+    }
+
+    fn write_block(&self, chunk_id: &str, index: u64, data: &[u8]) -> io::Result<()> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.dir.join(data_file_name(chunk_id)))?;
+        file.write_all_at(data, index * self.block_size)?;
+        file.sync_data()
+    }
+
+    /// Take least recently used blocks out of the index until there is room for one more block
+    /// below the low-water mark. Their data stays until passed to `discard`.
+    fn evict(&self, state: &mut State) -> Evicted {
+        let low_water = self.capacity / 8 * LOW_WATER_EIGHTHS;
+        let mut victims = vec![];
+        while state.bytes + self.block_size > low_water {
+            let Some((tick, key)) = state.by_use.pop_first() else {
+                break;
+            };
+            debug!("disk cache: evicting {} block {} (last use {})", key.0, key.1, tick);
+            state.last_use.remove(&key);
+            state.bytes -= self.block_size;
+            victims.push(key);
+        }
+        self.take_out(state, victims)
+    }
+
+    /// Clear the bits of `blocks`, which are no longer in the use order, and mark them as being
+    /// evicted.
+    fn take_out(&self, state: &mut State, blocks: Vec<(String, u64)>) -> Evicted {
+        if blocks.is_empty() {
+            return Evicted::default();
+        }
+        state.epoch += 1;
+        let epoch = state.epoch;
+        for (chunk_id, index) in &blocks {
+            if let Some(entry) = state.chunks.get_mut(chunk_id) {
+                entry.clear(*index);
+                entry.evicted = epoch;
+            }
+            state.evicting.insert((chunk_id.clone(), *index));
+        }
+
+        // A chunk with a write in progress keeps its data file, even if it has no blocks left.
+        let emptied: Vec<String> = state.chunks.iter()
+            .filter(|(id, entry)| entry.is_empty() && !state.writing.iter().any(|(writing, _)| writing == *id))
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in &emptied {
+            state.chunks.remove(id);
+            state.removing.insert(id.clone());
+        }
+        Evicted { blocks, chunks: emptied }
+    }
+
+    /// Discard the data of evicted blocks, once the index no longer claims them.
+    fn discard(&self, evicted: Evicted) -> io::Result<()> {
+        if evicted.blocks.is_empty() {
+            return Ok(());
+        }
+        let result = self.persist().and_then(|()| {
+            for id in &evicted.chunks {
+                fs::remove_file(self.dir.join(data_file_name(id)))?;
+            }
+            for (chunk_id, index) in evicted.blocks.iter().filter(|(id, _)| !evicted.chunks.contains(id)) {
+                let file = OpenOptions::new().write(true).open(self.dir.join(data_file_name(chunk_id)))?;
+                punch_hole(&file, index * self.block_size, self.block_size)?;
+            }
+            Ok(())
+        });
+
+        let mut state = self.inner.lock().unwrap();
+        for key in &evicted.blocks {
+            state.evicting.remove(key);
+        }
+        for id in &evicted.chunks {
+            state.removing.remove(id);
+        }
+        result
+    }
+
+    /// Atomically replace the on-disk index with the current state.
+    fn persist(&self) -> io::Result<()> {
+        let _persisting = self.persisting.lock().unwrap();
+        let index = {
+            let mut state = self.inner.lock().unwrap();
+            state.unsaved = 0;
+            Index {
+                version: INDEX_VERSION,
+                block_size: self.block_size,
+                chunks: state.chunks.clone(),
+            }
+        };
+        let tmp = self.dir.join(format!("{}.tmp", INDEX_FILE));
+        let file = File::create(&tmp)?;
+        serde_json::to_writer(&file, &index)?;
+        file.sync_all()?;
+        fs::rename(&tmp, self.dir.join(INDEX_FILE))?;
+        sync_dir(&self.dir)
+    }
+
+    /// Write out the index if any blocks were added since it was last saved.
+    pub fn flush(&self) -> io::Result<()> {
+        if self.inner.lock().unwrap().unsaved > 0 {
+            self.persist()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DiskCache {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            error!("disk cache {:?}: saving index: {}", self.dir, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK: u64 = 16;
+
+    fn block(n: u8) -> Vec<u8> {
+        vec![n; BLOCK as usize]
+    }
+
+    fn data_files(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name.ends_with(".data"))
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn reopen_reloads_the_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = DiskCache::open(dir.path(), BLOCK, 1 << 20).unwrap();
+        cache.insert("a", 0, &block(1));
+        cache.insert("a", 3, &block(2));
+        cache.insert("b/c", 1, &block(3));
+        drop(cache);
+
+        let cache = DiskCache::open(dir.path(), BLOCK, 1 << 20).unwrap();
+        assert_eq!(cache.get("a", 0, BLOCK as usize), Some(block(1)));
+        assert_eq!(cache.get("a", 3, BLOCK as usize), Some(block(2)));
+        assert_eq!(cache.get("b/c", 1, BLOCK as usize), Some(block(3)));
+        assert_eq!(cache.get("a", 1, BLOCK as usize), None);
+        assert_eq!(cache.get("b/c", 0, BLOCK as usize), None);
+    }
+
+    #[test]
+    fn index_with_another_block_size_is_discarded() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = DiskCache::open(dir.path(), BLOCK, 1 << 20).unwrap();
+        cache.insert("a", 0, &block(1));
+        drop(cache);
+
+        let cache = DiskCache::open(dir.path(), BLOCK * 2, 1 << 20).unwrap();
+        assert_eq!(cache.get("a", 0, BLOCK as usize), None);
+        assert!(data_files(dir.path()).is_empty());
+    }
+
+    #[test]
+    fn unreadable_index_is_discarded() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = DiskCache::open(dir.path(), BLOCK, 1 << 20).unwrap();
+        cache.insert("a", 0, &block(1));
+        drop(cache);
+        fs::write(dir.path().join(INDEX_FILE), "{\"version\": 1, \"bl").unwrap();
+
+        let cache = DiskCache::open(dir.path(), BLOCK, 1 << 20).unwrap();
+        assert_eq!(cache.get("a", 0, BLOCK as usize), None);
+        assert!(data_files(dir.path()).is_empty());
+    }
+
+    #[test]
+    fn orphan_data_files_are_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = DiskCache::open(dir.path(), BLOCK, 1 << 20).unwrap();
+        cache.insert("a", 0, &block(1));
+        drop(cache);
+        fs::write(dir.path().join("stray.data"), block(9)).unwrap();
+
+        let cache = DiskCache::open(dir.path(), BLOCK, 1 << 20).unwrap();
+        assert_eq!(data_files(dir.path()), ["a.data"]);
+        assert_eq!(cache.get("a", 0, BLOCK as usize), Some(block(1)));
+    }
+
+    #[test]
+    fn blocks_not_in_the_saved_index_are_not_served_after_a_crash() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = DiskCache::open(dir.path(), BLOCK, 1 << 20).unwrap();
+        cache.insert("a", 0, &block(1));
+        cache.flush().unwrap();
+        cache.insert("a", 1, &block(2));
+        cache.insert("b", 0, &block(3));
+        // Crash before the index is saved again.
+        std::mem::forget(cache);
+
+        let cache = DiskCache::open(dir.path(), BLOCK, 1 << 20).unwrap();
+        assert_eq!(cache.get("a", 0, BLOCK as usize), Some(block(1)));
+        assert_eq!(cache.get("a", 1, BLOCK as usize), None);
+        assert_eq!(cache.get("b", 0, BLOCK as usize), None);
+        assert_eq!(data_files(dir.path()), ["a.data"]);
+    }
+
+    #[test]
+    fn eviction_keeps_to_the_size_cap() {
+        let dir = tempfile::tempdir().unwrap();
+        let capacity = 8 * BLOCK;
+        let cache = DiskCache::open(dir.path(), BLOCK, capacity).unwrap();
+        for i in 0..40 {
+            cache.insert(&format!("c{}", i % 5), i, &block(i as u8));
+            let state = cache.inner.lock().unwrap();
+            assert!(state.bytes <= capacity, "{} bytes after {} inserts", state.bytes, i + 1);
+        }
+
+        // Each eviction made room for more than one block.
+        assert!(cache.inner.lock().unwrap().epoch <= (40 - 8) / 2);
+
+        // The most recent block is kept and the oldest are gone.
+        assert_eq!(cache.get("c4", 39, BLOCK as usize), Some(block(39)));
+        assert_eq!(cache.get("c0", 0, BLOCK as usize), None);
+        let held = (0..40).filter(|&i| cache.get(&format!("c{}", i % 5), i, BLOCK as usize).is_some()).count();
+        assert!(held as u64 <= capacity / BLOCK);
+        drop(cache);
+
+        let cache = DiskCache::open(dir.path(), BLOCK, capacity).unwrap();
+        assert!(cache.inner.lock().unwrap().bytes <= capacity);
+        assert_eq!(cache.get("c4", 39, BLOCK as usize), Some(block(39)));
+    }
+
+    #[test]
+    fn blocks_of_a_replaced_chunk_are_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let old = ChunkMeta { size: 32, md5: Some("aa".to_owned()), modified: None };
+        let new = ChunkMeta { md5: Some("bb".to_owned()), ..old.clone() };
+        let cache = DiskCache::open(dir.path(), BLOCK, 1 << 20).unwrap();
+        cache.check_version("a", &old);
+        cache.check_version("b", &old);
+        cache.insert("a", 0, &block(1));
+        cache.insert("b", 0, &block(2));
+        // Only the first check after opening counts.
+        cache.check_version("a", &new);
+        assert_eq!(cache.get("a", 0, BLOCK as usize), Some(block(1)));
+        drop(cache);
+
+        let cache = DiskCache::open(dir.path(), BLOCK, 1 << 20).unwrap();
+        assert!(!cache.is_checked("a"));
+        cache.check_version("a", &new);
+        cache.check_version("b", &old);
+        assert_eq!(cache.get("a", 0, BLOCK as usize), None);
+        assert_eq!(cache.get("b", 0, BLOCK as usize), Some(block(2)));
+        assert_eq!(data_files(dir.path()), ["b.data"]);
+
+        // Blocks cached from now on are of the new version.
+        cache.insert("a", 0, &block(3));
+        drop(cache);
+        let cache = DiskCache::open(dir.path(), BLOCK, 1 << 20).unwrap();
+        cache.check_version("a", &new);
+        assert_eq!(cache.get("a", 0, BLOCK as usize), Some(block(3)));
+    }
+}
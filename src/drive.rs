@@ -0,0 +1,659 @@
+// DriveStore :: Chunk objects stored as files in Google Drive.
+//
+// Chunk IDs are Drive file IDs. Content is fetched through the Drive v3 `alt=media` endpoint with
+// a `Range` header.
+//
+// Every response is checked before its body is used. Rate limiting (429, or 403 with a
+// rate-limit reason) and server errors are retried according to the store's `RetryPolicy`;
+// anything else that isn't a success becomes a `DriveError`.
+//
+// Ranged reads are held to what was asked for: a 206 must carry a `Content-Range` starting at the
+// requested offset and a body of exactly that length, and a body cut short is fetched again. A
+// server which ignores `Range` and sends the whole object gets the wanted slice cut out of the
+// stream, as long as that doesn't mean downloading an unreasonable amount first.
+//
+// Each chunk's `md5Checksum` and `modifiedTime` are looked up on first access, and the ETag of
+// the first download is kept and sent back as `If-Range` on later ones. A different ETag, or the
+// whole object coming back where a range was asked for and the metadata no longer matching,
+// means the chunk was replaced.
+//
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read};
+use std::sync::RwLock;
+use std::thread;
+
+use reqwest::StatusCode;
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use reqwest::header::{
+    AUTHORIZATION,
+    CONTENT_RANGE,
+    ETAG,
+    IF_RANGE,
+    RANGE,
+};
+use serde::Deserialize;
+
+use crate::auth::Authenticator;
+use crate::error::FsError;
+use crate::http::{self, RetryPolicy};
+use crate::store::{ChunkMeta, ChunkStore, StoreResult, Versions};
+
+/// Where the Drive API lives; file metadata and content are under `/drive/v3/files`, uploads
+/// under `/upload/drive/v3/files`.
+const API_ROOT: &str = "https://www.googleapis.com";
+
+/// When a ranged request is answered with the whole object, at most this many bytes ahead of the
+/// range are read and thrown away to get to it; further in, the response is rejected instead.
+const MAX_IGNORED_RANGE_SKIP: u64 = 64 << 20;
+
+/// 403 reasons which mean "slow down" rather than "not allowed".
+const RATE_LIMIT_REASONS: &[&str] = &["rateLimitExceeded", "userRateLimitExceeded"];
+
+pub struct DriveStore {
+    auth: Authenticator,
+    client: Client,
+    retry: RetryPolicy,
+    /// `API_ROOT`, except in tests.
+    root: String,
+    versions: Versions,
+    /// ETag of each chunk's first download.
+    etags: RwLock<HashMap<String, String>>,
+}
+
+/// A response from Drive which wasn't a success.
+#[derive(Debug)]
+pub struct DriveError {
+    pub status: StatusCode,
+    /// The `reason` of the first entry in Google's error body, e.g. `rateLimitExceeded`.
+    pub reason: Option<String>,
+    pub message: String,
+}
+
+impl DriveError {
+    fn from_response(resp: Response) -> DriveError {
+        let status = resp.status();
+        let body = resp.text().unwrap_or_default();
+        match serde_json::from_str::<ErrorBody>(&body) {
+            Ok(ErrorBody { error }) => DriveError {
+                status,
+                reason: error.errors.into_iter().next().map(|e| e.reason),
+                message: error.message,
+            },
+            Err(_) => DriveError {
+                status,
+                reason: None,
+                message: body.trim().to_owned(),
+            },
+        }
+    }
+
+    /// Whether the same request might succeed if tried again later.
+    pub fn is_retryable(&self) -> bool {
+        match self.status {
+            StatusCode::TOO_MANY_REQUESTS => true,
+            StatusCode::FORBIDDEN => self.reason.as_deref()
+                .is_some_and(|reason| RATE_LIMIT_REASONS.contains(&reason)),
+            status => status.is_server_error(),
+        }
+    }
+}
+
+impl fmt::Display for DriveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "drive returned {}", self.status)?;
+        if let Some(reason) = &self.reason {
+            write!(f, " ({})", reason)?;
+        }
+        if !self.message.is_empty() {
+            write!(f, ": {}", self.message)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for DriveError {}
+
+/// Outcome of reading the body of a ranged request.
+enum Ranged {
+    Data(Vec<u8>),
+    /// The body ended early; the description says how.
+    Short(String),
+}
+
+/// Parse `Content-Range: bytes first-last/total`, with `total` as `None` if given as `*`.
+fn parse_content_range(value: &str) -> Option<(u64, u64, Option<u64>)> {
+    let (range, total) = value.strip_prefix("bytes ")?.split_once('/')?;
+    let (first, last) = range.split_once('-')?;
+    let total = match total {
+        "*" => None,
+        total => Some(total.parse().ok()?),
+    };
+    Some((first.parse().ok()?, last.parse().ok()?, total))
+}
+
+#[derive(Deserialize)]
+struct ErrorBody {
+    error: ErrorDetail,
+}
+
+#[derive(Deserialize)]
+struct ErrorDetail {
+    #[serde(default)]
+    message: String,
+    #[serde(default)]
+    errors: Vec<ErrorItem>,
+}
+
+#[derive(Deserialize)]
+struct ErrorItem {
+    #[serde(default)]
+    reason: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FileMetadata {
+    size: String,
+    md5_checksum: Option<String>,
+    modified_time: Option<String>,
+}
+
+impl DriveStore {
+    pub fn new(auth: Authenticator, client: Client, retry: RetryPolicy) -> DriveStore {
+        DriveStore {
+            auth,
+            client,
+            retry,
+            root: API_ROOT.to_owned(),
+            versions: Versions::default(),
+            etags: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// GET `url`, optionally restricted to the inclusive byte range `range` and made conditional
+    /// on the ETag `if_range`.
+    fn get(&self, url: &str, range: Option<(u64, u64)>, if_range: Option<&str>) -> StoreResult<Response> {
+        self.send(url, || {
+            let mut req = self.client.get(url);
+            if let Some((start_byte, end_byte)) = range {
+                req = req.header(RANGE, format!("bytes={start_byte}-{end_byte}"));
+            }
+            if let Some(etag) = if_range {
+                req = req.header(IF_RANGE, etag);
+            }
+            req
+        })
+    }
+
+    /// Send the request made by `build` to `url` with a bearer token.
+    ///
+    /// Only successful responses are returned. Transient failures are retried with backoff, and
+    /// a rejected token is refreshed once.
+    fn send(&self, url: &str, build: impl Fn() -> RequestBuilder) -> StoreResult<Response> {
+        let send = |access_token: &str| {
+            build().header(AUTHORIZATION, format!("Bearer {access_token}")).send()
+        };
+
+        let mut attempt = 0;
+        let mut refreshed = false;
+        loop {
+            let access_token = self.auth.access_token()?;
+            let last_attempt = attempt + 1 >= self.retry.max_attempts;
+            let (error, retry_after) = match send(&access_token) {
+                Ok(resp) if resp.status().is_success() => return Ok(resp),
+                Ok(resp) if resp.status() == StatusCode::UNAUTHORIZED && !refreshed => {
+                    // The token was revoked or expired early; get a new one and try once more.
+                    warn!("{}: access token rejected, refreshing", url);
+                    self.auth.invalidate(&access_token);
+                    refreshed = true;
+                    continue;
+                }
+                Ok(resp) => {
+                    let retry_after = http::retry_after(resp.headers());
+                    let error = DriveError::from_response(resp);
+                    if !error.is_retryable() || last_attempt {
+                        return Err(error.into());
+                    }
+                    (error.to_string(), retry_after)
+                }
+                Err(e) if http::is_transient(&e) && !last_attempt => (e.to_string(), None),
+                Err(e) => return Err(e.into()),
+            };
+
+            let delay = self.retry.delay(attempt, retry_after);
+            warn!("{}: {}; retrying in {:?} (attempt {} of {})",
+                  url, error, delay, attempt + 2, self.retry.max_attempts);
+            thread::sleep(delay);
+            attempt += 1;
+        }
+    }
+
+    /// Send requests to `root` instead of Google.
+    #[cfg(test)]
+    fn at(self, root: &str) -> DriveStore {
+        DriveStore { root: root.to_owned(), ..self }
+    }
+
+    fn media_url(&self, file_id: &str) -> String {
+        format!("{}/drive/v3/files/{file_id}?supportsAllDrives=true&supportsTeamDrives=true&alt=media", self.root)
+    }
+
+    /// Download `chunk_id`, whole or just the bytes in `range`, failing if it has been replaced
+    /// since it was first read.
+    fn download(&self, chunk_id: &str, range: Option<(u64, u64)>) -> StoreResult<Response> {
+        self.version(chunk_id)?;
+        // If-Range only works with strong validators.
+        let known = self.etags.read().unwrap().get(chunk_id).cloned();
+        let if_range = known.as_deref().filter(|etag| range.is_some() && !etag.starts_with("W/"));
+
+        let resp = self.get(&self.media_url(chunk_id), range, if_range)?;
+        if let Some(etag) = resp.headers().get(ETAG).and_then(|v| v.to_str().ok()) {
+            let first = match &known {
+                Some(known) => known.clone(),
+                None => self.etags.write().unwrap().entry(chunk_id.to_owned()).or_insert_with(|| etag.to_owned()).clone(),
+            };
+            if first != etag {
+                error!("chunk {} changed on the backend while mounted: ETag was {}, now {}", chunk_id, first, etag);
+                return Err(FsError::ChunkChanged { id: chunk_id.to_owned() });
+            }
+        }
+        if if_range.is_some() && resp.status() == StatusCode::OK {
+            // Either the condition failed or the server ignores Range; the metadata tells which.
+            self.versions.check(chunk_id, self.metadata(chunk_id)?)?;
+        }
+        Ok(resp)
+    }
+
+    /// Check a response to a request for bytes `start..=end` and read the part of it that was
+    /// asked for.
+    fn read_ranged(resp: Response, start: u64, end: u64) -> StoreResult<Ranged> {
+        match resp.status() {
+            StatusCode::PARTIAL_CONTENT => Self::read_partial(resp, start, end),
+            StatusCode::OK => Self::read_ignored_range(resp, start, end),
+            status => Err(FsError::BadResponse(format!("unexpected {} to a ranged request", status))),
+        }
+    }
+
+    fn read_partial(resp: Response, start: u64, end: u64) -> StoreResult<Ranged> {
+        let header = resp.headers().get(CONTENT_RANGE)
+            .ok_or_else(|| FsError::BadResponse("206 without Content-Range".to_owned()))?;
+        let (first, last, total) = header.to_str().ok()
+            .and_then(parse_content_range)
+            .ok_or_else(|| FsError::BadResponse(format!("malformed Content-Range {:?}", header)))?;
+        // The range may only come back shorter than requested if that is where the object ends.
+        let ends_early = last < end && total != Some(last + 1);
+        if first != start || last > end || last < first || ends_early {
+            return Err(FsError::BadResponse(
+                format!("asked for bytes {}-{}, got Content-Range {:?}", start, end, header)));
+        }
+
+        let expected = last - first + 1;
+        let data = match resp.bytes() {
+            Ok(data) => data,
+            Err(e) => return Ok(Ranged::Short(format!("body interrupted: {}", e))),
+        };
+        match (data.len() as u64).cmp(&expected) {
+            Ordering::Less =>
+                Ok(Ranged::Short(format!("expected {} bytes, got {}", expected, data.len()))),
+            Ordering::Greater => Err(FsError::BadResponse(
+                format!("expected {} bytes, got {}", expected, data.len()))),
+            Ordering::Equal => Ok(Ranged::Data(data.to_vec())),
+        }
+    }
+
+    /// The server sent the whole object; skip to `start` and keep only the requested bytes.
+    fn read_ignored_range(mut resp: Response, start: u64, end: u64) -> StoreResult<Ranged> {
+        if start > MAX_IGNORED_RANGE_SKIP {
+            return Err(FsError::BadResponse(
+                format!("server ignored Range; refusing to skip {} bytes to reach it", start)));
+        }
+        warn!("server ignored Range for bytes {}-{}; slicing the full response", start, end);
+        let total = resp.content_length();
+        let short = |got: u64| total.is_some_and(|total| total > got);
+
+        let skipped = match io::copy(&mut (&mut resp).take(start), &mut io::sink()) {
+            Ok(skipped) => skipped,
+            Err(e) => return Ok(Ranged::Short(format!("body interrupted: {}", e))),
+        };
+        if skipped < start {
+            return Ok(if short(skipped) {
+                Ranged::Short(format!("body ended after {} bytes", skipped))
+            } else {
+                Ranged::Data(vec![])
+            });
+        }
+        let mut data = vec![];
+        if let Err(e) = (&mut resp).take(end - start + 1).read_to_end(&mut data) {
+            return Ok(Ranged::Short(format!("body interrupted: {}", e)));
+        }
+        if (data.len() as u64) < end - start + 1 && short(start + data.len() as u64) {
+            return Ok(Ranged::Short(format!("body ended after {} bytes", start + data.len() as u64)));
+        }
+        Ok(Ranged::Data(data))
+    }
+}
+
+impl ChunkStore for DriveStore {
+    fn read_range(&self, chunk_id: &str, start: u64, end: u64) -> StoreResult<Vec<u8>> {
+        debug!("drive: {} bytes {}-{}", chunk_id, start, end);
+        let mut attempt = 0;
+        loop {
+            let resp = self.download(chunk_id, Some((start, end)))?;
+            let why = match Self::read_ranged(resp, start, end)? {
+                Ranged::Data(data) => return Ok(data),
+                Ranged::Short(why) => why,
+            };
+            attempt += 1;
+            if attempt >= self.retry.max_attempts {
+                return Err(FsError::BadResponse(format!("{} bytes {}-{}: {}", chunk_id, start, end, why)));
+            }
+            let delay = self.retry.delay(attempt - 1, None);
+            warn!("drive: {} bytes {}-{}: {}; retrying in {:?}", chunk_id, start, end, why, delay);
+            thread::sleep(delay);
+        }
+    }
+
+    fn read_all(&self, chunk_id: &str) -> StoreResult<Vec<u8>> {
+        debug!("drive: {} whole file", chunk_id);
+        let resp = self.download(chunk_id, None)?;
+        Ok(resp.bytes()?.to_vec())
+    }
+
+    fn write(&self, chunk_id: &str, data: &[u8]) -> StoreResult<()> {
+        debug!("drive: replacing {} with {} bytes", chunk_id, data.len());
+        let url = format!("{}/upload/drive/v3/files/{chunk_id}?supportsAllDrives=true&uploadType=media", self.root);
+        self.send(&url, || self.client.patch(&url).body(data.to_vec()))?;
+        self.versions.forget(chunk_id);
+        self.etags.write().unwrap().remove(chunk_id);
+        Ok(())
+    }
+
+    fn metadata(&self, chunk_id: &str) -> StoreResult<ChunkMeta> {
+        let url = format!("{}/drive/v3/files/{chunk_id}?supportsAllDrives=true&fields=size,md5Checksum,modifiedTime", self.root);
+        let meta: FileMetadata = self.get(&url, None, None)?.json()?;
+        Ok(ChunkMeta {
+            size: meta.size.parse()
+                .map_err(|_| FsError::BadResponse(format!("{}: bad size {:?}", chunk_id, meta.size)))?,
+            md5: meta.md5_checksum,
+            modified: meta.modified_time,
+        })
+    }
+
+    fn version(&self, chunk_id: &str) -> StoreResult<ChunkMeta> {
+        self.versions.record(chunk_id, || self.metadata(chunk_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{AuthError, Token, TokenSource};
+    use crate::libc_extras::libc;
+    use crate::stand_in::{self, StandIn};
+    use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+    use std::time::{Duration, Instant};
+
+    /// Hands out `token-1`, `token-2`, ... on each fetch.
+    struct Tokens(AtomicUsize);
+
+    impl TokenSource for Tokens {
+        fn fetch(&self, _client: &Client) -> Result<Token, AuthError> {
+            let n = self.0.fetch_add(1, AtomicOrdering::Relaxed) + 1;
+            Ok(Token {
+                access_token: format!("token-{}", n),
+                expires_at: Instant::now() + Duration::from_secs(3600),
+            })
+        }
+    }
+
+    fn store(server: &StandIn) -> DriveStore {
+        let client = Client::builder().no_proxy().build().unwrap();
+        let auth = Authenticator::new(Box::new(Tokens(AtomicUsize::new(0))), client.clone());
+        let retry = RetryPolicy {
+            max_attempts: 4,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_secs(5),
+        };
+        DriveStore::new(auth, client, retry).at(&server.url)
+    }
+
+    /// The stored object every test reads from.
+    fn object() -> Vec<u8> {
+        (0..100).collect()
+    }
+
+    fn metadata() -> Vec<u8> {
+        stand_in::json(serde_json::json!({
+            "size": "100",
+            "md5Checksum": "0123456789abcdef",
+            "modifiedTime": "2024-01-01T00:00:00.000Z",
+        }))
+    }
+
+    fn partial(content_range: &str, body: &[u8]) -> Vec<u8> {
+        stand_in::response("206 Partial Content", &[("Content-Range", content_range)], body)
+    }
+
+    fn status(status: &str, headers: &[(&str, &str)], reason: &str) -> Vec<u8> {
+        let body = serde_json::json!({"error": {"message": status, "errors": [{"reason": reason}]}});
+        stand_in::response(status, &[headers, &[("Content-Type", "application/json")]].concat(), body.to_string())
+    }
+
+    /// Read bytes `start..=end` of a chunk from a server answering with `responses` once it has
+    /// given the chunk's metadata.
+    fn read_range(responses: Vec<Vec<u8>>, start: u64, end: u64) -> (StoreResult<Vec<u8>>, Vec<stand_in::Request>) {
+        let server = StandIn::serve([vec![metadata()], responses].concat());
+        let result = store(&server).read_range("c", start, end);
+        (result, server.requests())
+    }
+
+    fn is_bad_response<T>(result: &StoreResult<T>) -> bool {
+        matches!(result, Err(FsError::BadResponse(_)))
+    }
+
+    #[test]
+    fn partial_content_is_read() {
+        let (result, requests) = read_range(vec![partial("bytes 10-19/100", &object()[10..20])], 10, 19);
+        assert_eq!(result.unwrap(), &object()[10..20]);
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].path, "/drive/v3/files/c?supportsAllDrives=true&fields=size,md5Checksum,modifiedTime");
+        assert!(requests[1].path.starts_with("/drive/v3/files/c?") && requests[1].path.ends_with("&alt=media"));
+        assert_eq!(requests[1].header("range"), Some("bytes=10-19"));
+        assert_eq!(requests[1].header("authorization"), Some("Bearer token-1"));
+    }
+
+    #[test]
+    fn partial_content_needs_a_content_range() {
+        let no_range = stand_in::response("206 Partial Content", &[], &object()[10..20]);
+        assert!(is_bad_response(&read_range(vec![no_range], 10, 19).0));
+        assert!(is_bad_response(&read_range(vec![partial("bytes ten-19/100", &object()[10..20])], 10, 19).0));
+    }
+
+    #[test]
+    fn partial_content_must_start_where_asked() {
+        let (result, requests) = read_range(vec![partial("bytes 5-14/100", &object()[5..15])], 10, 19);
+        assert!(is_bad_response(&result));
+        assert_eq!(requests.len(), 2);
+    }
+
+    #[test]
+    fn partial_content_may_only_end_early_at_the_end_of_the_object() {
+        let (result, _) = read_range(vec![partial("bytes 90-99/100", &object()[90..])], 90, 119);
+        assert_eq!(result.unwrap(), &object()[90..]);
+        let (result, _) = read_range(vec![partial("bytes 10-15/100", &object()[10..16])], 10, 19);
+        assert!(is_bad_response(&result));
+        let (result, _) = read_range(vec![partial("bytes 10-25/100", &object()[10..26])], 10, 19);
+        assert!(is_bad_response(&result));
+    }
+
+    #[test]
+    fn partial_content_body_must_match_the_range() {
+        // Too long is an error.
+        let (result, _) = read_range(vec![partial("bytes 10-19/100", &object()[10..21])], 10, 19);
+        assert!(is_bad_response(&result));
+
+        // Too short is fetched again.
+        let (result, requests) = read_range(vec![
+            partial("bytes 10-19/100", &object()[10..15]),
+            partial("bytes 10-19/100", &object()[10..20]),
+        ], 10, 19);
+        assert_eq!(result.unwrap(), &object()[10..20]);
+        assert_eq!(requests.len(), 3);
+    }
+
+    #[test]
+    fn ignored_range_is_sliced_out_of_the_whole_object() {
+        let whole = stand_in::response("200 OK", &[], object());
+        let (result, requests) = read_range(vec![whole], 10, 19);
+        assert_eq!(result.unwrap(), &object()[10..20]);
+        assert_eq!(requests.len(), 2);
+    }
+
+    #[test]
+    fn ignored_range_too_far_in_is_rejected() {
+        let start = MAX_IGNORED_RANGE_SKIP + 1;
+        let whole = stand_in::response("200 OK", &[], object());
+        let (result, requests) = read_range(vec![whole], start, start + 9);
+        assert!(is_bad_response(&result));
+        assert_eq!(requests.len(), 2);
+    }
+
+    #[test]
+    fn rate_limits_and_server_errors_are_retried() {
+        let server = StandIn::serve(vec![
+            status("429 Too Many Requests", &[], "rateLimitExceeded"),
+            status("503 Service Unavailable", &[], "backendError"),
+            status("403 Forbidden", &[], "userRateLimitExceeded"),
+            metadata(),
+        ]);
+        assert_eq!(store(&server).metadata("c").unwrap().size, 100);
+        assert_eq!(server.requests().len(), 4);
+    }
+
+    #[test]
+    fn retries_give_up_after_max_attempts() {
+        let server = StandIn::serve(vec![status("503 Service Unavailable", &[], "backendError"); 4]);
+        match store(&server).metadata("c") {
+            Err(FsError::Status(e)) => assert_eq!(e.status, StatusCode::SERVICE_UNAVAILABLE),
+            other => panic!("expected a 503, got {:?}", other.map(|_| ())),
+        }
+        assert_eq!(server.requests().len(), 4);
+    }
+
+    #[test]
+    fn permanent_errors_are_not_retried() {
+        let server = StandIn::serve(vec![status("404 Not Found", &[], "notFound")]);
+        let e = store(&server).metadata("c").unwrap_err();
+        assert_eq!(e.errno(), libc::ENOENT);
+        assert_eq!(server.requests().len(), 1);
+
+        let server = StandIn::serve(vec![status("403 Forbidden", &[], "insufficientFilePermissions")]);
+        let e = store(&server).metadata("c").unwrap_err();
+        assert_eq!(e.errno(), libc::EACCES);
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[test]
+    fn retry_after_is_honoured() {
+        let server = StandIn::serve(vec![
+            status("429 Too Many Requests", &[("Retry-After", "1")], "rateLimitExceeded"),
+            metadata(),
+        ]);
+        let started = Instant::now();
+        store(&server).metadata("c").unwrap();
+        assert!(started.elapsed() >= Duration::from_secs(1), "retried after {:?}", started.elapsed());
+    }
+
+    #[test]
+    fn rejected_token_is_refreshed_once() {
+        let server = StandIn::serve(vec![status("401 Unauthorized", &[], "authError"), metadata()]);
+        store(&server).metadata("c").unwrap();
+        let tokens: Vec<_> = server.requests().iter().map(|r| r.header("authorization").unwrap().to_owned()).collect();
+        assert_eq!(tokens, ["Bearer token-1", "Bearer token-2"]);
+
+        let server = StandIn::serve(vec![status("401 Unauthorized", &[], "authError"); 2]);
+        let e = store(&server).metadata("c").unwrap_err();
+        assert_eq!(e.errno(), libc::EACCES);
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    fn tagged(etag: &str, content_range: &str, body: &[u8]) -> Vec<u8> {
+        stand_in::response("206 Partial Content", &[("Content-Range", content_range), ("ETag", etag)], body)
+    }
+
+    #[test]
+    fn changed_etag_is_a_replaced_chunk() {
+        let server = StandIn::serve(vec![
+            metadata(),
+            tagged("\"v1\"", "bytes 10-19/100", &object()[10..20]),
+            tagged("\"v2\"", "bytes 20-29/100", &object()[20..30]),
+        ]);
+        let store = store(&server);
+        assert_eq!(store.read_range("c", 10, 19).unwrap(), &object()[10..20]);
+        let e = store.read_range("c", 20, 29).unwrap_err();
+        assert!(matches!(&e, FsError::ChunkChanged { id } if id == "c"));
+        assert_eq!(e.errno(), libc::ESTALE);
+    }
+
+    #[test]
+    fn later_reads_are_conditional_on_the_first_etag() {
+        let server = StandIn::serve(vec![
+            metadata(),
+            tagged("\"v1\"", "bytes 10-19/100", &object()[10..20]),
+            tagged("\"v1\"", "bytes 20-29/100", &object()[20..30]),
+        ]);
+        let store = store(&server);
+        store.read_range("c", 10, 19).unwrap();
+        assert_eq!(store.read_range("c", 20, 29).unwrap(), &object()[20..30]);
+        let requests = server.requests();
+        assert_eq!(requests[1].header("if-range"), None);
+        assert_eq!(requests[2].header("if-range"), Some("\"v1\""));
+    }
+
+    #[test]
+    fn weak_etags_are_not_sent_as_if_range() {
+        let server = StandIn::serve(vec![
+            metadata(),
+            tagged("W/\"v1\"", "bytes 10-19/100", &object()[10..20]),
+            tagged("W/\"v1\"", "bytes 20-29/100", &object()[20..30]),
+        ]);
+        let store = store(&server);
+        store.read_range("c", 10, 19).unwrap();
+        store.read_range("c", 20, 29).unwrap();
+        assert!(server.requests().iter().all(|r| r.header("if-range").is_none()));
+    }
+
+    #[test]
+    fn whole_object_in_answer_to_if_range_checks_the_metadata() {
+        let replaced = stand_in::json(serde_json::json!({
+            "size": "100",
+            "md5Checksum": "fedcba9876543210",
+            "modifiedTime": "2024-02-01T00:00:00.000Z",
+        }));
+        let serve = |metadata_now| StandIn::serve(vec![
+            metadata(),
+            tagged("\"v1\"", "bytes 10-19/100", &object()[10..20]),
+            stand_in::response("200 OK", &[], object()),
+            metadata_now,
+        ]);
+
+        // The server just ignores Range: the chunk is the same, so the slice is served.
+        let server = serve(metadata());
+        let same = store(&server);
+        same.read_range("c", 10, 19).unwrap();
+        assert_eq!(same.read_range("c", 20, 29).unwrap(), &object()[20..30]);
+        let requests = server.requests();
+        assert_eq!(requests.len(), 4);
+        assert!(requests[3].path.contains("fields=size,md5Checksum,modifiedTime"));
+
+        // The condition failed because the chunk was replaced.
+        let server = serve(replaced);
+        let changed = store(&server);
+        changed.read_range("c", 10, 19).unwrap();
+        assert_eq!(changed.read_range("c", 20, 29).unwrap_err().errno(), libc::ESTALE);
+        assert_eq!(server.requests().len(), 4);
+    }
+}
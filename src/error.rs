@@ -0,0 +1,175 @@
+// FsError :: Everything that can go wrong fetching file data, and the errno each maps to.
+//
+// Backends, caches and decryption all report failures as an `FsError`, so that the FUSE layer
+// can turn any of them into an error code for the caller instead of giving up on the request.
+//
+
+use std::fmt;
+use std::io;
+
+use reqwest::StatusCode;
+
+use crate::auth::AuthError;
+use crate::drive::DriveError;
+use crate::libc_extras::libc;
+use crate::manifest::ManifestError;
+
+#[derive(Debug)]
+pub enum FsError {
+    Io(io::Error),
+    /// The request never got a response.
+    Http(reqwest::Error),
+    /// The backend answered with an error status.
+    Status(DriveError),
+    Auth(AuthError),
+    /// A chunk could not be decrypted, or the key is unusable.
+    Decrypt(String),
+    Manifest(ManifestError),
+    /// The backend answered, but not with what was asked for.
+    BadResponse(String),
+    /// The chunk was replaced on the backend since it was first read.
+    ChunkChanged { id: String },
+}
+
+impl FsError {
+    /// The errno to hand back to the kernel for this failure.
+    pub fn errno(&self) -> libc::c_int {
+        match self {
+            FsError::Io(e) => io_errno(e),
+            FsError::Http(e) => http_errno(e),
+            FsError::Status(e) => match e.status {
+                // Only seen once the store has run out of retries, so nothing the caller can
+                // usefully try again.
+                _ if e.is_retryable() => libc::EIO,
+                StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => libc::EACCES,
+                StatusCode::NOT_FOUND => libc::ENOENT,
+                StatusCode::REQUEST_TIMEOUT => libc::ETIMEDOUT,
+                _ => libc::EIO,
+            },
+            FsError::Auth(AuthError::Http(e)) => http_errno(e),
+            FsError::Auth(_) => libc::EACCES,
+            FsError::Decrypt(_) | FsError::Manifest(_) | FsError::BadResponse(_) => libc::EIO,
+            FsError::ChunkChanged { .. } => libc::ESTALE,
+        }
+    }
+}
+
+fn io_errno(e: &io::Error) -> libc::c_int {
+    if let Some(errno) = e.raw_os_error() {
+        return errno;
+    }
+    match e.kind() {
+        io::ErrorKind::NotFound => libc::ENOENT,
+        io::ErrorKind::PermissionDenied => libc::EACCES,
+        io::ErrorKind::TimedOut => libc::ETIMEDOUT,
+        io::ErrorKind::InvalidInput => libc::EINVAL,
+        _ => libc::EIO,
+    }
+}
+
+fn http_errno(e: &reqwest::Error) -> libc::c_int {
+    if e.is_timeout() {
+        libc::ETIMEDOUT
+    } else {
+        libc::EIO
+    }
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FsError::Io(e) => e.fmt(f),
+            FsError::Http(e) => write!(f, "request failed: {}", e),
+            FsError::Status(e) => e.fmt(f),
+            FsError::Auth(e) => write!(f, "authentication failed: {}", e),
+            FsError::Decrypt(msg) => write!(f, "decryption failed: {}", msg),
+            FsError::Manifest(e) => e.fmt(f),
+            FsError::BadResponse(msg) => write!(f, "bad response: {}", msg),
+            FsError::ChunkChanged { id } => write!(f, "chunk {} changed on the backend", id),
+        }
+    }
+}
+
+impl std::error::Error for FsError {}
+
+impl From<io::Error> for FsError {
+    fn from(e: io::Error) -> FsError {
+        FsError::Io(e)
+    }
+}
+
+impl From<reqwest::Error> for FsError {
+    fn from(e: reqwest::Error) -> FsError {
+        FsError::Http(e)
+    }
+}
+
+impl From<DriveError> for FsError {
+    fn from(e: DriveError) -> FsError {
+        FsError::Status(e)
+    }
+}
+
+impl From<AuthError> for FsError {
+    fn from(e: AuthError) -> FsError {
+        FsError::Auth(e)
+    }
+}
+
+impl From<ManifestError> for FsError {
+    fn from(e: ManifestError) -> FsError {
+        FsError::Manifest(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    fn status(status: StatusCode, reason: Option<&str>) -> FsError {
+        FsError::Status(DriveError {
+            status,
+            reason: reason.map(str::to_owned),
+            message: status.to_string(),
+        })
+    }
+
+    #[test]
+    fn statuses() {
+        assert_eq!(status(StatusCode::UNAUTHORIZED, None).errno(), libc::EACCES);
+        assert_eq!(status(StatusCode::FORBIDDEN, Some("insufficientFilePermissions")).errno(), libc::EACCES);
+        assert_eq!(status(StatusCode::NOT_FOUND, Some("notFound")).errno(), libc::ENOENT);
+        assert_eq!(status(StatusCode::REQUEST_TIMEOUT, None).errno(), libc::ETIMEDOUT);
+        assert_eq!(status(StatusCode::BAD_REQUEST, None).errno(), libc::EIO);
+    }
+
+    #[test]
+    fn exhausted_retries_are_io_errors() {
+        assert_eq!(status(StatusCode::SERVICE_UNAVAILABLE, None).errno(), libc::EIO);
+        assert_eq!(status(StatusCode::INTERNAL_SERVER_ERROR, None).errno(), libc::EIO);
+        assert_eq!(status(StatusCode::TOO_MANY_REQUESTS, None).errno(), libc::EIO);
+        assert_eq!(status(StatusCode::FORBIDDEN, Some("rateLimitExceeded")).errno(), libc::EIO);
+    }
+
+    #[test]
+    fn timeouts() {
+        // A server which accepts connections but never answers.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = reqwest::blocking::Client::builder().timeout(Duration::from_millis(50)).build().unwrap();
+        let e = client.get(format!("http://{}/", listener.local_addr().unwrap())).send().unwrap_err();
+        assert!(e.is_timeout());
+        assert_eq!(FsError::Http(e).errno(), libc::ETIMEDOUT);
+        assert_eq!(FsError::Io(io::ErrorKind::TimedOut.into()).errno(), libc::ETIMEDOUT);
+    }
+
+    #[test]
+    fn everything_else() {
+        assert_eq!(FsError::Decrypt("bad token".into()).errno(), libc::EIO);
+        assert_eq!(FsError::BadResponse("short body".into()).errno(), libc::EIO);
+        assert_eq!(FsError::Auth(AuthError::UnsupportedCredentials("x".into())).errno(), libc::EACCES);
+        assert_eq!(FsError::ChunkChanged { id: "c".into() }.errno(), libc::ESTALE);
+        assert_eq!(FsError::Io(io::Error::from_raw_os_error(libc::ENOSPC)).errno(), libc::ENOSPC);
+    }
+}
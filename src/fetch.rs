@@ -0,0 +1,451 @@
+// Fetcher :: Block-level access to stored chunk data through the memory and disk caches.
+//
+// Offsets here are into the object as stored on the backend, which for encrypted chunks is
+// ciphertext; the caches never hold decrypted data. Shared between the FUSE worker threads and
+// the read-ahead workers.
+//
+// Blocks missing from both caches are fetched in runs, and long runs are split into sub-requests
+// of at most `request_size` bytes. When a read needs several sub-requests, from one chunk or
+// many, the caller fetches the first and a pool of fetch threads the rest, all at once; the
+// pieces are put back in order before the read returns.
+//
+// A block is only fetched by one thread at a time. Whoever finds it missing first claims it, and
+// reads which want it meanwhile, whether they come from other FUSE threads or catch up with a
+// prefetch in progress, wait for that fetch instead of making their own.
+//
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use crate::cache::BlockCache;
+use crate::disk_cache::DiskCache;
+use crate::error::FsError;
+use crate::manifest::Chunk;
+use crate::store::{ChunkStore, StoreResult};
+
+#[derive(Clone, Copy, Debug)]
+pub struct FetchConfig {
+    /// Largest range asked of the backend in one request; rounded down to whole blocks.
+    pub request_size: u64,
+    /// Number of threads fetching sub-requests alongside the caller; with none, the caller
+    /// fetches them one after another.
+    pub workers: usize,
+}
+
+pub struct Fetcher {
+    pub store: Arc<dyn ChunkStore>,
+    pub cache: BlockCache,
+    pub disk_cache: Option<DiskCache>,
+    pub pool: FetchPool,
+    /// Blocks being fetched, by chunk ID and block index.
+    pending: Mutex<HashMap<(String, u64), Arc<Pending>>>,
+}
+
+/// A fetch of one block in progress.
+#[derive(Default)]
+struct Pending {
+    done: Mutex<bool>,
+    finished: Condvar,
+}
+
+impl Pending {
+    fn wait(&self) {
+        let mut done = self.done.lock().unwrap();
+        while !*done {
+            done = self.finished.wait(done).unwrap();
+        }
+    }
+
+    fn finish(&self) {
+        *self.done.lock().unwrap() = true;
+        self.finished.notify_all();
+    }
+}
+
+/// Blocks one thread has undertaken to fetch; reads wanting them wait until it is dropped,
+/// whether the fetch worked or not.
+struct Claim<'a> {
+    fetcher: &'a Fetcher,
+    keys: Vec<(String, u64)>,
+}
+
+impl Claim<'_> {
+    /// Claim block `index` of `chunk_id`, unless another thread is already fetching it, in which
+    /// case return its fetch to wait for.
+    fn take(&mut self, chunk_id: &str, index: u64) -> Option<Arc<Pending>> {
+        let key = (chunk_id.to_owned(), index);
+        let mut pending = self.fetcher.pending.lock().unwrap();
+        if let Some(fetch) = pending.get(&key) {
+            return Some(Arc::clone(fetch));
+        }
+        pending.insert(key.clone(), Arc::default());
+        self.keys.push(key);
+        None
+    }
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        let mut pending = self.fetcher.pending.lock().unwrap();
+        for key in &self.keys {
+            if let Some(fetch) = pending.remove(key) {
+                fetch.finish();
+            }
+        }
+    }
+}
+
+impl Fetcher {
+    pub fn new(store: Arc<dyn ChunkStore>, cache: BlockCache, disk_cache: Option<DiskCache>, pool: FetchPool) -> Fetcher {
+        Fetcher {
+            store,
+            cache,
+            disk_cache,
+            pool,
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Read bytes `start..=end` of each of the stored chunks in `ranges`, going through the
+    /// caches, and return them in the same order.
+    pub fn read_ranges(&self, ranges: &[(&Chunk, u64, u64)]) -> StoreResult<Vec<Vec<u8>>> {
+        let block_size = self.cache.block_size();
+        let mut blocks: Blocks = ranges.iter()
+            .map(|&(chunk, start, end)| (start / block_size..=end / block_size)
+                .map(|index| self.cache.get(&chunk.id, index)
+                    .or_else(|| self.read_disk_block(chunk, index)))
+                .collect())
+            .collect();
+
+        // Fetch the missing blocks nobody else is fetching, then wait for the others.
+        let mut claim = Claim { fetcher: self, keys: Vec::new() };
+        let mut waits = Vec::new();
+        let mut mine = Vec::new();
+        for (range, (&(chunk, start, _), slots)) in ranges.iter().zip(&blocks).enumerate() {
+            let first = start / block_size;
+            mine.push((first..).zip(slots).enumerate().map(|(slot, (index, block))| {
+                if block.is_some() {
+                    return false;
+                }
+                match claim.take(&chunk.id, index) {
+                    Some(fetch) => {
+                        waits.push((range, slot, fetch));
+                        false
+                    }
+                    None => true,
+                }
+            }).collect::<Vec<_>>());
+        }
+        self.fetch_into(ranges, &mut blocks, &mine)?;
+        drop(claim);
+
+        for (range, slot, fetch) in waits {
+            fetch.wait();
+            let (chunk, start, _) = ranges[range];
+            blocks[range][slot] = self.cache.get(&chunk.id, start / block_size + slot as u64);
+        }
+        // Whatever the fetches waited for didn't leave in the cache is fetched here after all.
+        let rest: Vec<Vec<bool>> = blocks.iter().map(|slots| slots.iter().map(Option::is_none).collect()).collect();
+        self.fetch_into(ranges, &mut blocks, &rest)?;
+
+        let data = ranges.iter().zip(blocks).map(|(&(_, start, end), blocks)| {
+            let mut buf = Vec::with_capacity((end - start + 1) as usize);
+            for (index, block) in (start / block_size..).zip(blocks) {
+                let block = block.unwrap();
+                let block_start = index * block_size;
+                let from = start.max(block_start) - block_start;
+                let to = end.min(block_start + block.len() as u64 - 1) - block_start;
+                buf.extend_from_slice(&block[from as usize..=to as usize]);
+            }
+            buf
+        }).collect();
+        Ok(data)
+    }
+
+    /// Fetch the blocks of `ranges` marked in `wanted` and put them in their slots in `blocks`.
+    fn fetch_into(&self, ranges: &[(&Chunk, u64, u64)], blocks: &mut Blocks, wanted: &[Vec<bool>]) -> StoreResult<()> {
+        let block_size = self.cache.block_size();
+        let mut runs = Vec::new();
+        for (&(chunk, start, _), wanted) in ranges.iter().zip(wanted) {
+            let first = start / block_size;
+            runs.extend(missing_runs(wanted.iter().copied())
+                .map(|(from, to)| (chunk, first + from, first + to)));
+        }
+        let mut fetched = self.fetch_runs(&runs)?.into_iter();
+        for (slots, wanted) in blocks.iter_mut().zip(wanted) {
+            for (from, to) in missing_runs(wanted.iter().copied()) {
+                for (slot, block) in slots[from as usize..=to as usize].iter_mut().zip(fetched.next().unwrap()) {
+                    *slot = Some(block);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Bring bytes `start..=end` of a stored chunk into the memory cache, fetching only the blocks
+    /// which aren't cached or being fetched already.
+    pub fn prefetch(&self, chunk: &Chunk, start: u64, end: u64) -> StoreResult<()> {
+        let block_size = self.cache.block_size();
+        let first = start / block_size;
+        let mut claim = Claim { fetcher: self, keys: Vec::new() };
+        let missing: Vec<bool> = (first..=end / block_size)
+            .map(|index| !self.cache.contains(&chunk.id, index)
+                && self.read_disk_block(chunk, index).is_none()
+                && claim.take(&chunk.id, index).is_none())
+            .collect();
+        let runs: Vec<_> = missing_runs(missing.into_iter())
+            .map(|(from, to)| (chunk, first + from, first + to))
+            .collect();
+        self.fetch_runs(&runs)?;
+        Ok(())
+    }
+
+    /// Fetch each run of blocks `first..=last` of a stored chunk from the backend, in sub-requests
+    /// made concurrently, and add them to the caches. Returns the blocks of each run.
+    fn fetch_runs(&self, runs: &[(&Chunk, u64, u64)]) -> StoreResult<Vec<Vec<Arc<Vec<u8>>>>> {
+        let block_size = self.cache.block_size();
+        let per_request = (self.pool.config.request_size / block_size).max(1);
+        let range = |chunk: &Chunk, first: u64, last: u64| {
+            (first * block_size, ((last + 1) * block_size).min(chunk.stored_size()) - 1)
+        };
+
+        // Split the runs into sub-requests of at most `per_request` blocks each.
+        let mut pieces = Vec::new();
+        for (run, &(chunk, first, last)) in runs.iter().enumerate() {
+            let mut from = first;
+            while from <= last {
+                let to = last.min(from + per_request - 1);
+                pieces.push((run, chunk, from, to));
+                from = to + 1;
+            }
+        }
+        let requests = pieces.iter().map(|&(_, chunk, from, to)| {
+            let (start, end) = range(chunk, from, to);
+            (chunk.id.clone(), start, end)
+        }).collect();
+        let results = self.pool.read_ranges(&self.store, requests);
+
+        let mut blocks = vec![Vec::new(); runs.len()];
+        for (&(run, chunk, from, to), data) in pieces.iter().zip(results) {
+            let data = data?;
+            let (start, end) = range(chunk, from, to);
+            if data.len() as u64 != end - start + 1 {
+                return Err(FsError::BadResponse(format!("expected {} bytes, got {}", end - start + 1, data.len())));
+            }
+            for (index, block) in (from..).zip(data.chunks(block_size as usize)) {
+                let block = Arc::new(block.to_vec());
+                self.cache.insert(&chunk.id, index, Arc::clone(&block));
+                if let Some(disk_cache) = self.disk_cache_for(chunk) {
+                    disk_cache.insert(&chunk.id, index, &block);
+                }
+                blocks[run].push(block);
+            }
+        }
+        Ok(blocks)
+    }
+
+    /// The disk cache, once it is known to hold nothing from an older version of `chunk`.
+    fn disk_cache_for(&self, chunk: &Chunk) -> Option<&DiskCache> {
+        let disk_cache = self.disk_cache.as_ref()?;
+        if !disk_cache.is_checked(&chunk.id) {
+            match self.store.version(&chunk.id) {
+                Ok(meta) => disk_cache.check_version(&chunk.id, &meta),
+                Err(e) => {
+                    warn!("chunk {}: cannot check cached blocks: {}", chunk.id, e);
+                    return None;
+                }
+            }
+        }
+        Some(disk_cache)
+    }
+
+    /// Look for a block in the disk cache, promoting it into memory if found.
+    fn read_disk_block(&self, chunk: &Chunk, index: u64) -> Option<Arc<Vec<u8>>> {
+        let block_size = self.cache.block_size();
+        let len = block_size.min(chunk.stored_size() - index * block_size);
+        let block = Arc::new(self.disk_cache_for(chunk)?.get(&chunk.id, index, len as usize)?);
+        self.cache.insert(&chunk.id, index, Arc::clone(&block));
+        Some(block)
+    }
+}
+
+/// Blocks of each of the ranges of a read, as far as they have been found.
+type Blocks = Vec<Vec<Option<Arc<Vec<u8>>>>>;
+
+/// The runs of consecutive `true`s in `missing`, as inclusive ranges of positions.
+fn missing_runs(missing: impl Iterator<Item = bool>) -> impl Iterator<Item = (u64, u64)> {
+    let mut missing = (0..).zip(missing).peekable();
+    std::iter::from_fn(move || {
+        let (from, _) = missing.find(|&(_, missing)| missing)?;
+        let mut to = from;
+        while let Some(&(index, true)) = missing.peek() {
+            to = index;
+            missing.next();
+        }
+        Some((from, to))
+    })
+}
+
+struct Request {
+    store: Arc<dyn ChunkStore>,
+    chunk_id: String,
+    start: u64,
+    end: u64,
+    /// Position of the request among those the caller is waiting for.
+    index: usize,
+    reply: Sender<(usize, StoreResult<Vec<u8>>)>,
+}
+
+/// Threads shared by every read for fetching ranges of stored chunks, so that the number of
+/// requests out at once stays bounded however many reads want them split.
+pub struct FetchPool {
+    config: FetchConfig,
+    requests: Sender<Request>,
+}
+
+impl FetchPool {
+    pub fn new(config: FetchConfig) -> FetchPool {
+        let (requests, queue) = mpsc::channel();
+        let queue = Arc::new(Mutex::new(queue));
+        for i in 0..config.workers {
+            let queue = Arc::clone(&queue);
+            thread::Builder::new()
+                .name(format!("fetch-{}", i))
+                .spawn(move || worker(&queue))
+                .expect("failed to spawn fetch thread");
+        }
+        FetchPool {
+            config,
+            requests,
+        }
+    }
+
+    /// Read each of `ranges` (chunk ID, start, end) of `store`, the first on this thread and the
+    /// rest on the pool, and return the results in the same order.
+    fn read_ranges(&self, store: &Arc<dyn ChunkStore>, ranges: Vec<(String, u64, u64)>) -> Vec<StoreResult<Vec<u8>>> {
+        let mut results: Vec<Option<StoreResult<Vec<u8>>>> = ranges.iter().map(|_| None).collect();
+        let mut ranges = ranges.into_iter().enumerate();
+        let Some((_, (chunk_id, start, end))) = ranges.next() else {
+            return Vec::new();
+        };
+
+        let (reply, replies) = mpsc::channel();
+        for (index, (chunk_id, start, end)) in ranges {
+            let request = Request {
+                store: Arc::clone(store),
+                chunk_id,
+                start,
+                end,
+                index,
+                reply: reply.clone(),
+            };
+            let unsent = if self.config.workers == 0 {
+                request
+            } else {
+                match self.requests.send(request) {
+                    Ok(()) => continue,
+                    Err(mpsc::SendError(request)) => request,
+                }
+            };
+            results[index] = Some(store.read_range(&unsent.chunk_id, unsent.start, unsent.end));
+        }
+        drop(reply);
+
+        results[0] = Some(store.read_range(&chunk_id, start, end));
+        for (index, result) in replies {
+            results[index] = Some(result);
+        }
+        results.into_iter()
+            .map(|result| result.unwrap_or_else(|| Err(FsError::Io(io::Error::other("fetch thread failed")))))
+            .collect()
+    }
+}
+
+fn worker(queue: &Mutex<Receiver<Request>>) {
+    loop {
+        let request = match queue.lock().unwrap().recv() {
+            Ok(request) => request,
+            Err(_) => return,
+        };
+        let result = request.store.read_range(&request.chunk_id, request.start, request.end);
+        let _ = request.reply.send((request.index, result));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{CountingStore, LocalStore};
+    use std::fs;
+    use std::path::Path;
+    use std::time::{Duration, SystemTime};
+
+    fn fetcher(chunks: &Path, cache: &Path) -> Fetcher {
+        Fetcher::new(
+            Arc::new(LocalStore::new(chunks)),
+            BlockCache::new(16, 1 << 20),
+            Some(DiskCache::open(cache, 16, 1 << 20).unwrap()),
+            FetchPool::new(FetchConfig { request_size: 1 << 20, workers: 0 }),
+        )
+    }
+
+    #[test]
+    fn disk_cache_is_not_served_for_a_replaced_chunk() {
+        let chunks = tempfile::tempdir().unwrap();
+        let cache = tempfile::tempdir().unwrap();
+        let chunk: Chunk = serde_json::from_value(serde_json::json!({
+            "id": "c", "start": 0, "end": 39, "size": 40,
+        })).unwrap();
+        let path = chunks.path().join("c");
+        fs::write(&path, [1; 40]).unwrap();
+
+        let first = fetcher(chunks.path(), cache.path());
+        assert_eq!(first.read_ranges(&[(&chunk, 0, 39)]).unwrap(), [vec![1; 40]]);
+        drop(first);
+
+        // Replaced between mounts, with the same size.
+        fs::write(&path, [2; 40]).unwrap();
+        let later = SystemTime::now() + Duration::from_secs(10);
+        fs::File::options().write(true).open(&path).unwrap().set_modified(later).unwrap();
+
+        let second = fetcher(chunks.path(), cache.path());
+        assert_eq!(second.read_ranges(&[(&chunk, 5, 20)]).unwrap(), [vec![2; 16]]);
+        drop(second);
+
+        // Rewritten behind the store's back with the same version information: the blocks
+        // cached from the second mount are what a third one reads.
+        fs::write(&path, [3; 40]).unwrap();
+        fs::File::options().write(true).open(&path).unwrap().set_modified(later).unwrap();
+        let third = fetcher(chunks.path(), cache.path());
+        assert_eq!(third.read_ranges(&[(&chunk, 0, 39)]).unwrap(), [[[2; 32].as_slice(), &[3; 8]].concat()]);
+    }
+
+    #[test]
+    fn a_block_is_fetched_once_however_many_want_it() {
+        let chunks = tempfile::tempdir().unwrap();
+        fs::write(chunks.path().join("c"), [7; 64]).unwrap();
+        let chunk: Chunk = serde_json::from_value(serde_json::json!({
+            "id": "c", "start": 0, "end": 63, "size": 64,
+        })).unwrap();
+        let store = Arc::new(CountingStore::new(chunks.path()).latency(Duration::from_millis(50)));
+        let fetcher = Fetcher::new(
+            Arc::clone(&store) as Arc<dyn ChunkStore>,
+            BlockCache::new(16, 1 << 20),
+            None,
+            FetchPool::new(FetchConfig { request_size: 1 << 20, workers: 0 }),
+        );
+
+        // A prefetch and several reads of the same blocks, all at once.
+        thread::scope(|scope| {
+            scope.spawn(|| fetcher.prefetch(&chunk, 0, 63).unwrap());
+            for _ in 0..4 {
+                scope.spawn(|| assert_eq!(fetcher.read_ranges(&[(&chunk, 0, 63)]).unwrap(), [vec![7; 64]]));
+            }
+        });
+        assert_eq!(store.reads(), 1);
+        assert!(fetcher.pending.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,95 @@
+// HandleTable :: FUSE file handles for open virtual files, and what each one knows.
+//
+// Every `open` of a virtual file gets a fresh handle number, which the kernel passes back on each
+// `read` and finally on `release`, when the state is dropped. Real files keep using their file
+// descriptor as the handle, so virtual handles are numbered from `FIRST_HANDLE` up, well clear of
+// any descriptor, and a handle that isn't in the table is taken to be a descriptor.
+//
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::readahead::Stream;
+
+/// The first handle number given out; file descriptors are `c_int`s, so always below this.
+pub const FIRST_HANDLE: u64 = 1 << 32;
+
+/// State for one open of a virtual file.
+pub struct OpenFile {
+    /// Name of the virtual file, as a key into the manifests.
+    pub name: OsString,
+    /// Where the reads through this handle have been going, for read-ahead.
+    pub stream: Mutex<Stream>,
+    pub stats: ReadStats,
+}
+
+/// Counters for the reads made through one handle.
+#[derive(Default)]
+pub struct ReadStats {
+    pub reads: AtomicU64,
+    pub bytes: AtomicU64,
+    pub errors: AtomicU64,
+}
+
+impl OpenFile {
+    pub fn new(name: OsString) -> OpenFile {
+        OpenFile {
+            name,
+            stream: Mutex::new(Stream::default()),
+            stats: ReadStats::default(),
+        }
+    }
+}
+
+impl ReadStats {
+    /// Count a read that returned `bytes` bytes, or failed if `None`.
+    pub fn record(&self, bytes: Option<usize>) {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        match bytes {
+            Some(bytes) => self.bytes.fetch_add(bytes as u64, Ordering::Relaxed),
+            None => self.errors.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+}
+
+pub struct HandleTable<T> {
+    next: AtomicU64,
+    open: RwLock<HashMap<u64, Arc<T>>>,
+}
+
+impl<T> HandleTable<T> {
+    pub fn new() -> HandleTable<T> {
+        HandleTable {
+            next: AtomicU64::new(FIRST_HANDLE),
+            open: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Add `state` under a handle number not in use, and return the number.
+    pub fn insert(&self, state: T) -> u64 {
+        let fh = self.next.fetch_add(1, Ordering::Relaxed);
+        self.open.write().unwrap().insert(fh, Arc::new(state));
+        fh
+    }
+
+    pub fn get(&self, fh: u64) -> Option<Arc<T>> {
+        self.open.read().unwrap().get(&fh).cloned()
+    }
+
+    /// Take the state for `fh` out of the table; it is dropped once no read still holds it.
+    pub fn remove(&self, fh: u64) -> Option<Arc<T>> {
+        self.open.write().unwrap().remove(&fh)
+    }
+
+    pub fn len(&self) -> usize {
+        self.open.read().unwrap().len()
+    }
+}
+
+impl<T> Default for HandleTable<T> {
+    fn default() -> HandleTable<T> {
+        HandleTable::new()
+    }
+}
@@ -0,0 +1,114 @@
+// Http :: The HTTP client shared by everything that talks to Google.
+//
+// One client is built at mount time and cloned into the token source and the Drive store, so
+// every FUSE worker thread draws on the same connection pool instead of setting up a new TLS
+// session per request.
+//
+// Also holds the retry policy for transient failures: capped exponential backoff with full
+// jitter, deferring to the server's `Retry-After` when it sends one.
+//
+
+use std::time::{Duration, SystemTime};
+
+use rand::Rng;
+use reqwest::blocking::Client;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+
+#[derive(Clone, Debug)]
+pub struct HttpConfig {
+    /// Most idle connections kept open to any one host.
+    pub pool_max_idle_per_host: usize,
+    /// How long an idle pooled connection is kept before it is closed.
+    pub pool_idle_timeout: Duration,
+    /// Interval for TCP keep-alive probes on open connections.
+    pub tcp_keepalive: Duration,
+    /// Limit on establishing a connection, including the TLS handshake.
+    pub connect_timeout: Duration,
+    /// Limit on a whole request, from sending it to reading the last byte of the body.
+    pub request_timeout: Duration,
+    /// Use HTTP/2 when the server offers it during the TLS handshake (ALPN); otherwise stick to
+    /// HTTP/1.1.
+    pub http2: bool,
+}
+
+impl Default for HttpConfig {
+    fn default() -> HttpConfig {
+        HttpConfig {
+            pool_max_idle_per_host: 32,
+            pool_idle_timeout: Duration::from_secs(90),
+            tcp_keepalive: Duration::from_secs(60),
+            connect_timeout: Duration::from_secs(10),
+            request_timeout: Duration::from_secs(60),
+            http2: true,
+        }
+    }
+}
+
+impl HttpConfig {
+    pub fn build_client(&self) -> reqwest::Result<Client> {
+        let builder = Client::builder()
+            .pool_max_idle_per_host(self.pool_max_idle_per_host)
+            .pool_idle_timeout(self.pool_idle_timeout)
+            .tcp_keepalive(self.tcp_keepalive)
+            .connect_timeout(self.connect_timeout)
+            .timeout(self.request_timeout);
+        let builder = if self.http2 {
+            builder.http2_adaptive_window(true)
+        } else {
+            builder.http1_only()
+        };
+        builder.build()
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Total tries per request, including the first.
+    pub max_attempts: u32,
+    /// Backoff ceiling before the first retry; it doubles with every attempt after that.
+    pub base_delay: Duration,
+    /// No single wait is longer than this, whatever the server asks for.
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 6,
+            base_delay: Duration::from_millis(250),
+            max_delay: Duration::from_secs(16),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// How long to wait after failed attempt number `attempt` (counting from zero).
+    ///
+    /// A `Retry-After` from the server is honoured as given; otherwise the wait is drawn
+    /// uniformly from zero up to the exponential ceiling, so that clients which failed together
+    /// don't all come back together.
+    pub fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        if let Some(wait) = retry_after {
+            return wait.min(self.max_delay);
+        }
+        let ceiling = self.base_delay
+            .saturating_mul(1 << attempt.min(16))
+            .min(self.max_delay);
+        rand::thread_rng().gen_range(Duration::ZERO..=ceiling)
+    }
+}
+
+/// Parse a `Retry-After` header, given either as delay-seconds or as an HTTP date.
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = httpdate::parse_http_date(value).ok()?;
+    Some(at.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+/// Whether a request which failed without a response is worth trying again.
+pub fn is_transient(e: &reqwest::Error) -> bool {
+    e.is_timeout() || e.is_connect() || e.is_request()
+}
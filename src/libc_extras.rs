@@ -77,36 +77,25 @@ pub mod libc {
     // Mac OS X does not support futimens; map it to futimes with lower precision.
     #[cfg(target_os = "macos")]
     pub unsafe fn futimens(fd: c_int, times: *const timespec) -> c_int {
-        use super::super::libc_wrappers;
         let mut times_osx = [timespec_to_timeval(&*times),
                              timespec_to_timeval(&*times)];
 
-        let mut stat: Option<stat> = None;
-
-        if (*times).tv_nsec == UTIME_OMIT {
-            // atime is unspecified
-
-            stat = match libc_wrappers::fstat(fd as u64) {
-                Ok(s) => Some(s),
-                Err(e) => return e,
-            };
-
-            times_osx[0].tv_sec = stat.unwrap().st_atime;
-            times_osx[0].tv_usec = stat.unwrap().st_atime_nsec as suseconds_t * 1000;
-        }
-
-        if (*times.offset(1)).tv_nsec == UTIME_OMIT {
-            // mtime is unspecified
-
-            if stat.is_none() {
-                stat = match libc_wrappers::fstat(fd as u64) {
-                    Ok(s) => Some(s),
-                    Err(e) => return e,
-                };
+        let atime_omitted = (*times).tv_nsec == UTIME_OMIT;
+        let mtime_omitted = (*times.offset(1)).tv_nsec == UTIME_OMIT;
+        if atime_omitted || mtime_omitted {
+            // Keep whichever time is unspecified as it is.
+            let mut stat: stat = ::std::mem::zeroed();
+            if -1 == fstat(fd, &mut stat) {
+                return -1;
+            }
+            if atime_omitted {
+                times_osx[0].tv_sec = stat.st_atime;
+                times_osx[0].tv_usec = stat.st_atime_nsec as suseconds_t * 1000;
+            }
+            if mtime_omitted {
+                times_osx[1].tv_sec = stat.st_mtime;
+                times_osx[1].tv_usec = stat.st_mtime_nsec as suseconds_t * 1000;
             }
-
-            times_osx[1].tv_sec = stat.unwrap().st_mtime;
-            times_osx[1].tv_usec = stat.unwrap().st_mtime_nsec as suseconds_t * 1000;
         }
 
         futimes(fd, &times_osx as *const timeval)
@@ -175,6 +164,10 @@ pub mod libc {
     #[cfg(target_os = "macos")]
     pub const XATTR_NOFOLLOW: c_int = 1;
 
+    // Linux reports a missing attribute as ENODATA, which Mac OS X calls ENOATTR.
+    #[cfg(target_os = "linux")]
+    pub const ENOATTR: c_int = ENODATA;
+
     #[cfg(target_os = "macos")]
     pub unsafe fn llistxattr(path: *const c_char, namebuf: *mut c_char, size: size_t) -> ssize_t {
         listxattr(path, namebuf, size, XATTR_NOFOLLOW)
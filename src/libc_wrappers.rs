@@ -91,15 +91,6 @@ pub fn lstat(path: OsString) -> Result<libc::stat64, libc::c_int> {
     Ok(buf)
 }
 
-pub fn fstat(fd: u64) -> Result<libc::stat64, libc::c_int> {
-    let mut buf: libc::stat64 = unsafe { mem::zeroed() };
-    if -1 == unsafe { libc::fstat64(fd as libc::c_int, &mut buf) } {
-        return Err(io::Error::last_os_error().raw_os_error().unwrap());
-    }
-
-    Ok(buf)
-}
-
 pub fn llistxattr(path: OsString, buf: &mut [u8]) -> Result<usize, libc::c_int> {
     let path_c = into_cstring!(path, "llistxattr");
 
@@ -5,15 +5,40 @@
 
 #![deny(rust_2018_idioms)]
 
+use std::collections::BTreeMap;
 use std::env;
 use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::Parser;
+
+use crate::config::Command;
 
 #[macro_use]
 extern crate log;
 
+mod auth;
+mod cache;
+mod config;
+mod crypt;
+mod disk_cache;
+mod drive;
+mod error;
+mod fetch;
+mod handles;
+mod http;
 mod libc_extras;
 mod libc_wrappers;
+mod manifest;
+mod namespace;
 mod passthrough;
+mod readahead;
+#[cfg(test)]
+mod stand_in;
+mod store;
 
 struct ConsoleLogger;
 
@@ -31,14 +56,368 @@ impl log::Log for ConsoleLogger {
 
 static LOGGER: ConsoleLogger = ConsoleLogger;
 
+/// Defaults for options not given on the command line or in the config file.
+const MANIFEST_DIR: &str = "manifests";
+const SOURCE_DIR: &str = "target";
+const MOUNTPOINT: &str = "mount";
+const CREDENTIALS: &str = "credentials.json";
+const THREADS: usize = 8;
+
+/// Reads from the backend are made in multiples of this many bytes.
+const BLOCK_SIZE: u64 = 1024 * 1024;
+
+/// Default upper bound on the memory used to cache remote data.
+const BLOCK_CACHE_CAPACITY: usize = 256 * 1024 * 1024;
+
+/// Default size cap for the on-disk cache, if one is enabled.
+const DISK_CACHE_CAPACITY: u64 = 16 * 1024 * 1024 * 1024;
+
+/// Default splitting of large backend reads.
+const FETCH: fetch::FetchConfig = fetch::FetchConfig {
+    request_size: 4 * 1024 * 1024,
+    workers: 8,
+};
+
+const READAHEAD: readahead::ReadAheadConfig = readahead::ReadAheadConfig {
+    initial_window: 1024 * 1024,
+    max_window: 32 * 1024 * 1024,
+    max_in_flight: 128 * 1024 * 1024,
+    workers: 4,
+};
+
 fn main() {
     log::set_logger(&LOGGER).unwrap();
-    log::set_max_level(log::LevelFilter::Debug);
-    let filesystem = passthrough::PassthroughFS {
-        target: "target".parse().unwrap(),
+    log::set_max_level(log::LevelFilter::Info);
+
+    let cli = config::Cli::parse();
+    let mut options = cli.options;
+    if let Some(path) = &cli.config {
+        match config::Options::load(path) {
+            Ok(file) => options = options.or(file),
+            Err(e) => {
+                error!("{}", e);
+                process::exit(2);
+            }
+        }
+    }
+    log::set_max_level(options.log_level.unwrap_or(log::LevelFilter::Info));
+
+    match cli.command {
+        Command::Mount { mountpoint } => mount(&options, &mountpoint_or_default(mountpoint, &options)),
+        Command::Unmount { mountpoint } => unmount(&mountpoint_or_default(mountpoint, &options)),
+        Command::Inspect { names } => inspect(&options, &names),
+        Command::Verify { names } => verify(&options, &names),
+        Command::Rotate { names } => rotate(&options, &names),
+    }
+}
+
+fn mountpoint_or_default(mountpoint: Option<PathBuf>, options: &config::Options) -> PathBuf {
+    mountpoint.or_else(|| options.mountpoint.clone()).unwrap_or_else(|| MOUNTPOINT.into())
+}
+
+fn manifest_dir(options: &config::Options) -> &Path {
+    options.manifests.as_deref().unwrap_or(Path::new(MANIFEST_DIR))
+}
+
+fn load_manifests(options: &config::Options) -> BTreeMap<OsString, manifest::Manifest> {
+    let files = match manifest::load_dir(manifest_dir(options)) {
+        Ok(files) => files,
+        Err(e) => {
+            error!("manifests: {}", e);
+            process::exit(1);
+        }
     };
+    for (name, manifest) in &files {
+        debug!("{:?}: {} chunks, {} bytes", name, manifest.chunks.len(), manifest.size());
+    }
+    files
+}
+
+/// Make sure every file named on the command line has a manifest.
+fn check_names(files: &BTreeMap<OsString, manifest::Manifest>, names: &[OsString]) {
+    if let Some(name) = names.iter().find(|name| !files.contains_key(*name)) {
+        error!("{:?}: no such file", name);
+        process::exit(1);
+    }
+}
+
+/// Connection settings for talking to Google, with defaults for whatever isn't given.
+fn http_config(options: &config::Options) -> http::HttpConfig {
+    let defaults = http::HttpConfig::default();
+    let seconds = |option: Option<u64>, default| option.map_or(default, Duration::from_secs);
+    http::HttpConfig {
+        pool_max_idle_per_host: options.http_pool_size.unwrap_or(defaults.pool_max_idle_per_host),
+        pool_idle_timeout: seconds(options.http_idle_timeout, defaults.pool_idle_timeout),
+        tcp_keepalive: seconds(options.http_keepalive, defaults.tcp_keepalive),
+        connect_timeout: seconds(options.connect_timeout, defaults.connect_timeout),
+        request_timeout: seconds(options.request_timeout, defaults.request_timeout),
+        http2: options.http2.unwrap_or(defaults.http2),
+    }
+}
+
+fn retry_policy(options: &config::Options) -> http::RetryPolicy {
+    let defaults = http::RetryPolicy::default();
+    http::RetryPolicy {
+        max_attempts: options.http_attempts.map_or(defaults.max_attempts, |attempts| attempts.max(1)),
+        ..defaults
+    }
+}
 
-    let fuse_args = [OsStr::new("-o"), OsStr::new("fsname=passthrufs")];
+/// Chunks come from Google Drive unless a local chunk directory is given.
+fn open_store(options: &config::Options, scope: &str) -> Box<dyn store::ChunkStore> {
+    if let Some(dir) = &options.chunk_dir {
+        info!("reading chunks from {:?}", dir);
+        return Box::new(store::LocalStore::new(dir));
+    }
+    let credentials = options.credentials.as_deref().unwrap_or(Path::new(CREDENTIALS));
+    let client = match http_config(options).build_client() {
+        Ok(client) => client,
+        Err(e) => {
+            error!("cannot create HTTP client: {}", e);
+            process::exit(1);
+        }
+    };
+    match auth::load_credentials(credentials, options.token_uri.as_deref(), scope, client.clone()) {
+        Ok(auth) => Box::new(drive::DriveStore::new(auth, client, retry_policy(options))),
+        Err(e) => {
+            error!("{:?}: {}", credentials, e);
+            process::exit(1);
+        }
+    }
+}
 
-    fuse_mt::mount(fuse_mt::FuseMT::new(filesystem, 1), "mount", &fuse_args[..]).unwrap();
+/// Look up the keys for every file with encrypted chunks, from a key file, the environment, or a
+/// passphrase.
+fn load_keys(options: &config::Options, files: &BTreeMap<OsString, manifest::Manifest>)
+    -> BTreeMap<OsString, crypt::Keyring>
+{
+    let source = crypt::KeySource::configured(options.key_file.as_deref(), |name| env::var(name).ok());
+    match crypt::resolve_keys(source.as_ref(), files) {
+        Ok(keys) => keys,
+        Err((name, e)) => {
+            error!("{:?}: {}", name, e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Remote data is also kept on disk across remounts if a cache directory is given.
+fn open_disk_cache(options: &config::Options) -> Option<disk_cache::DiskCache> {
+    let dir = options.cache_dir.as_ref()?;
+    let capacity = options.cache_size.unwrap_or(DISK_CACHE_CAPACITY);
+    match disk_cache::DiskCache::open(dir, BLOCK_SIZE, capacity) {
+        Ok(disk_cache) => Some(disk_cache),
+        Err(e) => {
+            error!("disk cache {:?}: {}", dir, e);
+            process::exit(1);
+        }
+    }
+}
+
+fn build_filesystem(
+    options: &config::Options,
+    files: BTreeMap<OsString, manifest::Manifest>,
+    store: Box<dyn store::ChunkStore>,
+    disk_cache: Option<disk_cache::DiskCache>,
+) -> passthrough::PassthroughFS {
+    let keys = load_keys(options, &files);
+
+    let fetcher = Arc::new(fetch::Fetcher::new(
+        Arc::from(store),
+        cache::BlockCache::new(BLOCK_SIZE, options.memory_cache_size.unwrap_or(BLOCK_CACHE_CAPACITY)),
+        disk_cache,
+        fetch::FetchPool::new(fetch::FetchConfig {
+            request_size: options.request_size.unwrap_or(FETCH.request_size),
+            workers: options.fetch_threads.unwrap_or(FETCH.workers),
+        }),
+    ));
+
+    // The mounting user owns the virtual files unless told otherwise.
+    let mut owner = passthrough::Owner::mounting_user();
+    owner.uid = options.uid.unwrap_or(owner.uid);
+    owner.gid = options.gid.unwrap_or(owner.gid);
+    owner.perm = options.mode.map_or(owner.perm, |mode| mode as u16);
+
+    let namespace = match namespace::Namespace::new(&files) {
+        Ok(namespace) => namespace,
+        Err(e) => {
+            error!("manifests: {}", e);
+            process::exit(1);
+        }
+    };
+
+    passthrough::PassthroughFS {
+        target: options.source.as_deref().unwrap_or(Path::new(SOURCE_DIR)).as_os_str().to_owned(),
+        files,
+        namespace,
+        readahead: readahead::ReadAhead::new(Arc::clone(&fetcher), READAHEAD),
+        fetcher,
+        decryptor: crypt::Decryptor::new(keys),
+        owner,
+        handles: handles::HandleTable::new(),
+    }
+}
+
+fn inspect(options: &config::Options, names: &[OsString]) {
+    let files = load_manifests(options);
+    check_names(&files, names);
+    for (name, manifest) in files.iter().filter(|(name, _)| names.is_empty() || names.contains(name)) {
+        println!("{}: {} bytes in {} chunks, modified {}", name.to_string_lossy(), manifest.size(),
+                 manifest.chunks.len(), httpdate::fmt_http_date(manifest.mtime()));
+        if let Some(path) = &manifest.path {
+            println!("  mounted at: /{}", path.display());
+        }
+        if let Some(manifest::Kdf::Scrypt { log_n, r, p, .. }) = &manifest.kdf {
+            println!("  passphrase key: scrypt log_n={} r={} p={}", log_n, r, p);
+        }
+        for chunk in &manifest.chunks {
+            println!("  {:#014x}-{:#014x} {:>12} {:<6} {} {}", chunk.start, chunk.end, chunk.size,
+                     format!("{:?}", chunk.encoding).to_lowercase(), chunk.id,
+                     chunk.checksum.as_deref().unwrap_or("-"));
+        }
+    }
+}
+
+/// Check that each chunk exists on the backend with the size and checksum the manifest gives,
+/// and that the keys open the encrypted ones.
+fn verify(options: &config::Options, names: &[OsString]) {
+    let mut files = load_manifests(options);
+    check_names(&files, names);
+    files.retain(|name, _| names.is_empty() || names.contains(name));
+    let store = open_store(options, auth::DRIVE_READONLY_SCOPE);
+
+    let mut ok = true;
+    for (name, manifest) in &files {
+        for chunk in &manifest.chunks {
+            let meta = match store.metadata(&chunk.id) {
+                Ok(meta) => meta,
+                Err(e) => {
+                    error!("{:?}: chunk {}: {}", name, chunk.id, e);
+                    ok = false;
+                    continue;
+                }
+            };
+            // Fernet tokens are bigger than what they hold by an amount that depends on padding.
+            if chunk.encoding != manifest::Encoding::Fernet && meta.size != chunk.stored_size() {
+                let e = manifest::ManifestError::SizeMismatch {
+                    id: chunk.id.clone(),
+                    expected: chunk.stored_size(),
+                    actual: meta.size,
+                };
+                error!("{:?}: {}", name, e);
+                ok = false;
+            }
+            if let (Some(expected), Some(actual)) = (&chunk.checksum, &meta.md5) {
+                if !expected.eq_ignore_ascii_case(actual) {
+                    error!("{:?}: chunk {}: manifest says md5 {}, but it is {}", name, chunk.id, expected, actual);
+                    ok = false;
+                }
+            }
+        }
+    }
+
+    let filesystem = build_filesystem(options, files, store, None);
+    ok &= filesystem.check_keys();
+    if !ok {
+        process::exit(1);
+    }
+    info!("{} files verified", filesystem.files.len());
+}
+
+/// Re-encrypt the encrypted chunks of the named files (all of them if none are named) with the
+/// newest key, recording the new checksums in their manifests.
+fn rotate(options: &config::Options, names: &[OsString]) {
+    let mut files = load_manifests(options);
+    check_names(&files, names);
+    let store = open_store(options, auth::DRIVE_SCOPE);
+    let keys = load_keys(options, &files);
+
+    let mut failed = false;
+    for (name, manifest) in files.iter_mut().filter(|(name, _)| names.is_empty() || names.contains(name)) {
+        let keyring = match keys.get(name) {
+            Some(keyring) => keyring,
+            None => continue,
+        };
+        let mut changed = false;
+        for chunk in manifest.chunks.iter_mut().filter(|c| c.encoding != manifest::Encoding::Plain) {
+            match crypt::rotate(&*store, keyring, chunk) {
+                Ok(Some(checksum)) => {
+                    chunk.checksum = Some(checksum);
+                    changed = true;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    error!("{:?}: chunk {}: {}", name, chunk.id, e);
+                    failed = true;
+                }
+            }
+        }
+        if changed {
+            let mut file_name = name.clone();
+            file_name.push(".json");
+            let path = manifest_dir(options).join(file_name);
+            if let Err(e) = manifest.save(&path) {
+                error!("{:?}: {}", path, e);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        process::exit(1);
+    }
+}
+
+fn mount(options: &config::Options, mountpoint: &Path) {
+    let files = load_manifests(options);
+    let store = open_store(options, auth::DRIVE_READONLY_SCOPE);
+
+    // Make sure the backend is reachable with these credentials before mounting anything.
+    for (name, manifest) in &files {
+        let chunk = &manifest.chunks[0];
+        match store.metadata(&chunk.id) {
+            Ok(meta) => debug!("{:?}: chunk {} is {} bytes", name, chunk.id, meta.size),
+            Err(e) => {
+                error!("{:?}: cannot stat chunk {}: {}", name, chunk.id, e);
+                process::exit(1);
+            }
+        }
+    }
+
+    let filesystem = build_filesystem(options, files, store, open_disk_cache(options));
+    if !filesystem.check_keys() {
+        process::exit(1);
+    }
+
+    let mut fuse_args: Vec<&OsStr> = vec![OsStr::new("-o"), OsStr::new("fsname=passthrufs")];
+    for option in &options.fuse_options {
+        fuse_args.push(OsStr::new("-o"));
+        fuse_args.push(OsStr::new(option));
+    }
+    let threads = options.threads.unwrap_or(THREADS);
+
+    info!("mounting {} files on {:?}", filesystem.files.len(), mountpoint);
+    if let Err(e) = fuse_mt::mount(fuse_mt::FuseMT::new(filesystem, threads), mountpoint, &fuse_args[..]) {
+        error!("mount {:?}: {}", mountpoint, e);
+        process::exit(1);
+    }
+}
+
+/// Unmount with fusermount where there is one, as it also works for unprivileged users.
+fn unmount(mountpoint: &Path) {
+    let status = match process::Command::new("fusermount").arg("-u").arg(mountpoint).status() {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound =>
+            process::Command::new("umount").arg(mountpoint).status(),
+        status => status,
+    };
+    match status {
+        Ok(status) if status.success() => {}
+        Ok(status) => {
+            error!("unmount {:?}: {}", mountpoint, status);
+            process::exit(1);
+        }
+        Err(e) => {
+            error!("unmount {:?}: {}", mountpoint, e);
+            process::exit(1);
+        }
+    }
 }
@@ -0,0 +1,460 @@
+// Manifest :: On-disk description of how a virtual file is split into remote chunks.
+//
+// A manifest is a JSON document listing every chunk of the virtual file in order, with the
+// inclusive byte range it covers. Each `*.json` file in the manifest directory describes one
+// virtual file; they are all loaded and validated once at mount time.
+//
+// Commands which change chunks (such as key rotation) write the manifest back, one chunk per
+// line as the files are laid out by hand.
+//
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+/// The only manifest format version this build understands.
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// Plaintext bytes per independently sealed block of an AEAD chunk.
+pub const AEAD_BLOCK_SIZE: u64 = 64 * 1024;
+
+/// Bytes each sealed block adds for its authentication tag.
+pub const AEAD_TAG_SIZE: u64 = 16;
+
+/// How a chunk's bytes are stored on the backend.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    /// Stored as-is; ranged reads map directly onto the remote object.
+    #[default]
+    Plain,
+    /// The whole chunk is a single Fernet token and must be fetched and decrypted at once.
+    Fernet,
+    /// A run of sealed blocks, each `AEAD_BLOCK_SIZE` bytes of plaintext (the last may be
+    /// shorter) followed by its tag, so any block can be fetched and verified on its own.
+    Aead,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Chunk {
+    /// Backend object ID (a Google Drive file ID).
+    pub id: String,
+    /// Offset of the first byte of this chunk within the virtual file.
+    pub start: u64,
+    /// Offset of the last byte of this chunk within the virtual file (inclusive).
+    pub end: u64,
+    /// Number of bytes of the virtual file this chunk provides.
+    pub size: u64,
+    /// Hex MD5 of the object as stored on the backend, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+    #[serde(default, skip_serializing_if = "Encoding::is_plain")]
+    pub encoding: Encoding,
+    /// Base64 salt for deriving this chunk's key; required for AEAD chunks.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub salt: Option<String>,
+}
+
+impl Chunk {
+    /// Number of sealed blocks in an AEAD chunk.
+    pub fn aead_blocks(&self) -> u64 {
+        self.size.div_ceil(AEAD_BLOCK_SIZE)
+    }
+
+    /// Size of the object as stored on the backend.
+    pub fn stored_size(&self) -> u64 {
+        match self.encoding {
+            Encoding::Aead => self.size + self.aead_blocks() * AEAD_TAG_SIZE,
+            _ => self.size,
+        }
+    }
+
+    /// The bytes of the stored object needed to read plaintext bytes `start..=end` of the chunk.
+    ///
+    /// For AEAD chunks this is every sealed block overlapping the range, tags included.
+    pub fn stored_range(&self, start: u64, end: u64) -> (u64, u64) {
+        match self.encoding {
+            Encoding::Aead => {
+                let sealed = AEAD_BLOCK_SIZE + AEAD_TAG_SIZE;
+                let first = start / AEAD_BLOCK_SIZE;
+                let last = end / AEAD_BLOCK_SIZE;
+                (first * sealed, ((last + 1) * sealed).min(self.stored_size()) - 1)
+            }
+            _ => (start, end),
+        }
+    }
+}
+
+/// How the key for encrypted chunks is derived from a passphrase.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "algorithm", rename_all = "lowercase")]
+pub enum Kdf {
+    Scrypt {
+        /// Base64-encoded salt.
+        salt: String,
+        log_n: u8,
+        r: u32,
+        p: u32,
+    },
+}
+
+fn compact<T: Serialize>(value: &T) -> Result<String, ManifestError> {
+    serde_json::to_string(value).map_err(ManifestError::Parse)
+}
+
+impl Encoding {
+    fn is_plain(&self) -> bool {
+        *self == Encoding::Plain
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub chunks: Vec<Chunk>,
+    /// Needed only when the key for encrypted chunks is given as a passphrase.
+    #[serde(default)]
+    pub kdf: Option<Kdf>,
+    /// When the virtual file last changed, in seconds since the epoch.
+    #[serde(default)]
+    pub modified: Option<u64>,
+    /// Where the virtual file appears in the mount, relative to its root; at its name in the
+    /// root if not given.
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    /// Modification time of the manifest file itself, used when `modified` is not given.
+    #[serde(skip)]
+    pub file_modified: Option<SystemTime>,
+}
+
+#[derive(Debug)]
+pub enum ManifestError {
+    Io(io::Error),
+    Parse(serde_json::Error),
+    UnsupportedVersion(u32),
+    Empty,
+    /// `end` is before `start`, `size` disagrees with the range, or the chunk reaches too far to
+    /// address.
+    BadRange { index: usize },
+    /// There are bytes between the previous chunk and this one which no chunk covers.
+    Gap { index: usize, expected: u64, found: u64 },
+    /// This chunk covers bytes which the previous chunk already covers.
+    Overlap { index: usize, expected: u64, found: u64 },
+    /// This chunk starts before the previous one; chunks must be listed in offset order.
+    OutOfOrder { index: usize },
+    /// An AEAD chunk has no salt to derive its key from.
+    MissingSalt { index: usize },
+    /// Loading one manifest out of a directory failed.
+    File { path: PathBuf, source: Box<ManifestError> },
+    /// The chunk's data turned out to be a different size from what the manifest says.
+    SizeMismatch { id: String, expected: u64, actual: u64 },
+    /// `path` is absolute or steps outside the mount.
+    BadPath { path: PathBuf },
+    /// Two virtual files want the same path, or one wants a path that another needs as a
+    /// directory.
+    PathConflict { path: PathBuf },
+}
+
+impl fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ManifestError::Io(e) => write!(f, "cannot read manifest: {}", e),
+            ManifestError::Parse(e) => write!(f, "cannot parse manifest: {}", e),
+            ManifestError::UnsupportedVersion(v) =>
+                write!(f, "unsupported manifest version {} (expected {})", v, MANIFEST_VERSION),
+            ManifestError::Empty => write!(f, "manifest has no chunks"),
+            ManifestError::BadRange { index } =>
+                write!(f, "chunk {}: range and size do not agree", index),
+            ManifestError::Gap { index, expected, found } =>
+                write!(f, "chunk {}: gap, expected start {} but found {}", index, expected, found),
+            ManifestError::Overlap { index, expected, found } =>
+                write!(f, "chunk {}: overlap, expected start {} but found {}", index, expected, found),
+            ManifestError::OutOfOrder { index } =>
+                write!(f, "chunk {}: starts before the previous chunk", index),
+            ManifestError::MissingSalt { index } => write!(f, "chunk {}: aead chunk has no salt", index),
+            ManifestError::File { path, source } => write!(f, "{:?}: {}", path, source),
+            ManifestError::SizeMismatch { id, expected, actual } =>
+                write!(f, "chunk {}: manifest says {} bytes, but it holds {}", id, expected, actual),
+            ManifestError::BadPath { path } =>
+                write!(f, "path {:?} must be relative, without . or .. components", path),
+            ManifestError::PathConflict { path } =>
+                write!(f, "more than one virtual file needs the path {:?}", path),
+        }
+    }
+}
+
+impl std::error::Error for ManifestError {}
+
+/// Load every `*.json` manifest in `dir`, keyed by file name minus the `.json` extension.
+///
+/// Any manifest failing to load or validate fails the whole directory.
+pub fn load_dir(dir: impl AsRef<Path>) -> Result<BTreeMap<OsString, Manifest>, ManifestError> {
+    let mut manifests = BTreeMap::new();
+    for entry in fs::read_dir(dir).map_err(ManifestError::Io)? {
+        let path = entry.map_err(ManifestError::Io)?.path();
+        if path.extension() != Some("json".as_ref()) || !path.is_file() {
+            continue;
+        }
+        let name = match path.file_stem() {
+            Some(stem) => stem.to_owned(),
+            None => continue,
+        };
+        let manifest = Manifest::load(&path)
+            .map_err(|e| ManifestError::File { path: path.clone(), source: Box::new(e) })?;
+        manifests.insert(name, manifest);
+    }
+    Ok(manifests)
+}
+
+impl Manifest {
+    /// Read, parse and validate the manifest at `path`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Manifest, ManifestError> {
+        let file = fs::File::open(path).map_err(ManifestError::Io)?;
+        let file_modified = file.metadata().and_then(|m| m.modified()).ok();
+        let text = io::read_to_string(file).map_err(ManifestError::Io)?;
+        let mut manifest: Manifest = serde_json::from_str(&text).map_err(ManifestError::Parse)?;
+        manifest.validate()?;
+        manifest.file_modified = file_modified;
+        Ok(manifest)
+    }
+
+    /// Atomically replace the manifest at `path` with this one.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), ManifestError> {
+        let path = path.as_ref();
+        let mut text = format!("{{\n  \"version\": {},\n", self.version);
+        if let Some(kdf) = &self.kdf {
+            text += &format!("  \"kdf\": {},\n", compact(kdf)?);
+        }
+        if let Some(modified) = self.modified {
+            text += &format!("  \"modified\": {},\n", modified);
+        }
+        if let Some(path) = &self.path {
+            text += &format!("  \"path\": {},\n", compact(path)?);
+        }
+        text += "  \"chunks\": [\n";
+        for (i, chunk) in self.chunks.iter().enumerate() {
+            let separator = if i + 1 < self.chunks.len() { "," } else { "" };
+            text += &format!("    {}{}\n", compact(chunk)?, separator);
+        }
+        text += "  ]\n}\n";
+
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let write = || -> io::Result<()> {
+            let mut file = fs::File::create(&tmp)?;
+            file.write_all(text.as_bytes())?;
+            file.sync_all()?;
+            fs::rename(&tmp, path)?;
+            // Make the rename itself durable, so a crash can't bring back the old manifest while
+            // the chunks it lists have been rewritten.
+            let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+            fs::File::open(dir)?.sync_all()
+        };
+        write().map_err(ManifestError::Io)
+    }
+
+    /// Check that the path (if any) stays inside the mount, and that the chunks are in order and
+    /// exactly tile `0..=size-1` with no gaps or overlaps.
+    pub fn validate(&self) -> Result<(), ManifestError> {
+        if self.version != MANIFEST_VERSION {
+            return Err(ManifestError::UnsupportedVersion(self.version));
+        }
+        if self.chunks.is_empty() {
+            return Err(ManifestError::Empty);
+        }
+        if let Some(path) = &self.path {
+            let mut components = path.components().peekable();
+            if components.peek().is_none() || !components.all(|c| matches!(c, Component::Normal(_))) {
+                return Err(ManifestError::BadPath { path: path.clone() });
+            }
+        }
+
+        let mut prev: Option<&Chunk> = None;
+        for (index, chunk) in self.chunks.iter().enumerate() {
+            // The end must leave room for the file size (one past it) to be representable.
+            let len = chunk.end.checked_sub(chunk.start).and_then(|n| n.checked_add(1));
+            if chunk.end == u64::MAX || len != Some(chunk.size) {
+                return Err(ManifestError::BadRange { index });
+            }
+            if chunk.encoding == Encoding::Aead
+                && chunk.aead_blocks().checked_mul(AEAD_TAG_SIZE).and_then(|tags| chunk.size.checked_add(tags)).is_none()
+            {
+                return Err(ManifestError::BadRange { index });
+            }
+            if chunk.encoding == Encoding::Aead && chunk.salt.is_none() {
+                return Err(ManifestError::MissingSalt { index });
+            }
+            let expected = prev.map_or(0, |p| p.end + 1);
+            if let Some(prev) = prev {
+                if chunk.start < prev.start {
+                    return Err(ManifestError::OutOfOrder { index });
+                }
+            }
+            if chunk.start > expected {
+                return Err(ManifestError::Gap { index, expected, found: chunk.start });
+            } else if chunk.start < expected {
+                return Err(ManifestError::Overlap { index, expected, found: chunk.start });
+            }
+            prev = Some(chunk);
+        }
+
+        Ok(())
+    }
+
+    /// Total size of the virtual file.
+    pub fn size(&self) -> u64 {
+        self.chunks.last().map_or(0, |c| c.end + 1)
+    }
+
+    /// When the virtual file last changed: as recorded in the manifest, or else when the
+    /// manifest itself was last written.
+    pub fn mtime(&self) -> SystemTime {
+        match self.modified {
+            Some(secs) => UNIX_EPOCH + Duration::from_secs(secs),
+            None => self.file_modified.unwrap_or(UNIX_EPOCH),
+        }
+    }
+
+    /// The first chunk which needs a key to read, if any.
+    pub fn first_encrypted(&self) -> Option<&Chunk> {
+        self.chunks.iter().find(|c| c.encoding != Encoding::Plain)
+    }
+
+    /// Split `offset..offset+length` into per-chunk pieces, in order.
+    ///
+    /// Each piece gives the chunk and the inclusive byte range to read from it, relative to the
+    /// start of the chunk. The range is cut short at the end of the file.
+    pub fn segments(&self, offset: u64, length: u32) -> Vec<Segment<'_>> {
+        let end = offset.saturating_add(u64::from(length)).min(self.size());
+        if offset >= end {
+            return vec![];
+        }
+        let first = self.chunks.partition_point(|c| c.end < offset);
+        self.chunks[first..].iter()
+            .take_while(|c| c.start < end)
+            .map(|chunk| Segment {
+                chunk,
+                start: offset.max(chunk.start) - chunk.start,
+                end: (end - 1).min(chunk.end) - chunk.start,
+            })
+            .collect()
+    }
+}
+
+/// The part of one chunk needed to satisfy a read.
+pub struct Segment<'a> {
+    pub chunk: &'a Chunk,
+    /// First byte to read, relative to the start of the chunk.
+    pub start: u64,
+    /// Last byte to read (inclusive), relative to the start of the chunk.
+    pub end: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+
+    fn manifest(chunks: &[(u64, u64)]) -> Manifest {
+        let chunks: Vec<_> = chunks.iter().enumerate()
+            .map(|(i, &(start, end))| serde_json::json!({
+                "id": format!("c{}", i),
+                "start": start,
+                "end": end,
+                "size": end.wrapping_sub(start).wrapping_add(1),
+            }))
+            .collect();
+        serde_json::from_value(serde_json::json!({"version": 1, "chunks": chunks})).unwrap()
+    }
+
+    #[test]
+    fn contiguous_chunks_are_valid() {
+        let manifest = manifest(&[(0, 9), (10, 10), (11, 99)]);
+        manifest.validate().unwrap();
+        assert_eq!(manifest.size(), 100);
+    }
+
+    #[test]
+    fn gaps_and_overlaps_are_rejected() {
+        assert!(matches!(manifest(&[(0, 9), (11, 20)]).validate(),
+                         Err(ManifestError::Gap { index: 1, expected: 10, found: 11 })));
+        assert!(matches!(manifest(&[(1, 9)]).validate(),
+                         Err(ManifestError::Gap { index: 0, expected: 0, found: 1 })));
+        assert!(matches!(manifest(&[(0, 9), (9, 20)]).validate(),
+                         Err(ManifestError::Overlap { index: 1, expected: 10, found: 9 })));
+    }
+
+    #[test]
+    fn chunks_must_be_in_order() {
+        assert!(matches!(manifest(&[(0, 9), (10, 19), (5, 9)]).validate(),
+                         Err(ManifestError::OutOfOrder { index: 2 })));
+    }
+
+    #[test]
+    fn version_and_emptiness_are_checked() {
+        let mut unsupported = manifest(&[(0, 9)]);
+        unsupported.version = MANIFEST_VERSION + 1;
+        assert!(matches!(unsupported.validate(), Err(ManifestError::UnsupportedVersion(2))));
+        assert!(matches!(manifest(&[]).validate(), Err(ManifestError::Empty)));
+    }
+
+    #[test]
+    fn bad_ranges_are_rejected_without_overflow() {
+        let mut wrong_size = manifest(&[(0, 9)]);
+        wrong_size.chunks[0].size = 11;
+        assert!(matches!(wrong_size.validate(), Err(ManifestError::BadRange { index: 0 })));
+        assert!(matches!(manifest(&[(0, 9), (10, 5)]).validate(), Err(ManifestError::BadRange { index: 1 })));
+        assert!(matches!(manifest(&[(0, u64::MAX)]).validate(), Err(ManifestError::BadRange { index: 0 })));
+        assert!(matches!(manifest(&[(0, 9), (10, u64::MAX)]).validate(),
+                         Err(ManifestError::BadRange { index: 1 })));
+
+        let mut aead = manifest(&[(0, u64::MAX - 1)]);
+        aead.chunks[0].encoding = Encoding::Aead;
+        aead.chunks[0].salt = Some(String::new());
+        assert!(matches!(aead.validate(), Err(ManifestError::BadRange { index: 0 })));
+    }
+
+    #[test]
+    fn saved_manifests_load_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("f.json");
+        let mut saved = manifest(&[(0, 9), (10, 99)]);
+        saved.chunks[1].checksum = Some("0123456789abcdef0123456789abcdef".into());
+        saved.save(&path).unwrap();
+        saved.save(&path).unwrap();
+
+        let loaded = Manifest::load(&path).unwrap();
+        assert_eq!(serde_json::to_value(&loaded.chunks).unwrap(), serde_json::to_value(&saved.chunks).unwrap());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn directories_load_every_json_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, chunks: &[(u64, u64)]| {
+            manifest(chunks).save(dir.path().join(name)).unwrap();
+        };
+        write("a.json", &[(0, 9)]);
+        write("b.json", &[(0, 9), (10, 19)]);
+        // Not manifests: other extensions, leftovers of `save` and directories.
+        write("notes.txt", &[(0, 9)]);
+        fs::write(dir.path().join("c.json.tmp"), "{").unwrap();
+        fs::create_dir(dir.path().join("d.json")).unwrap();
+
+        let manifests = load_dir(dir.path()).unwrap();
+        assert_eq!(manifests.keys().collect::<Vec<_>>(), ["a", "b"]);
+        assert_eq!(manifests[OsStr::new("b")].size(), 20);
+
+        write("broken.json", &[(0, 9), (11, 19)]);
+        let e = load_dir(dir.path()).unwrap_err();
+        assert!(matches!(&e, ManifestError::File { path, source }
+                         if path.ends_with("broken.json") && matches!(**source, ManifestError::Gap { .. })));
+        assert!(e.to_string().contains("broken.json"), "{}", e);
+    }
+}
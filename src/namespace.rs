@@ -0,0 +1,138 @@
+// Namespace :: Where the virtual files appear among the local ones.
+//
+// The mount shows the source directory, with each virtual file overlaid at the path its manifest
+// gives (or at its name in the root), hiding any local entry there. Directories leading to a
+// virtual file exist in the mount whether or not they exist locally. Everything in the overlay is
+// read-only; everything else is the local file.
+//
+
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::path::{Component, Path, PathBuf};
+
+use fuse_mt::FileType;
+
+use crate::manifest::{Manifest, ManifestError};
+
+pub struct Namespace {
+    /// Name of the virtual file at each mount path.
+    files: BTreeMap<PathBuf, OsString>,
+    /// Entries the overlay puts in each directory, by the directory's mount path.
+    dirs: BTreeMap<PathBuf, BTreeMap<OsString, FileType>>,
+}
+
+impl Namespace {
+    /// Lay out the virtual files `files`, keyed by name.
+    pub fn new(files: &BTreeMap<OsString, Manifest>) -> Result<Namespace, ManifestError> {
+        let mut namespace = Namespace {
+            files: BTreeMap::new(),
+            dirs: BTreeMap::new(),
+        };
+        namespace.dirs.insert(PathBuf::from("/"), BTreeMap::new());
+
+        for (name, manifest) in files {
+            let relative = manifest.path.as_deref().unwrap_or(Path::new(name));
+            let mut components = relative.components().peekable();
+            if components.peek().is_none() || !components.all(|c| matches!(c, Component::Normal(_))) {
+                return Err(ManifestError::BadPath { path: relative.to_owned() });
+            }
+            let path = Path::new("/").join(relative);
+            if namespace.files.contains_key(&path) || namespace.dirs.contains_key(&path) {
+                return Err(ManifestError::PathConflict { path });
+            }
+
+            let mut child = path.as_path();
+            let mut kind = FileType::RegularFile;
+            while let (Some(parent), Some(child_name)) = (child.parent(), child.file_name()) {
+                if namespace.files.contains_key(parent) {
+                    return Err(ManifestError::PathConflict { path: parent.to_owned() });
+                }
+                namespace.dirs.entry(parent.to_owned()).or_default().insert(child_name.to_owned(), kind);
+                kind = FileType::Directory;
+                child = parent;
+            }
+            namespace.files.insert(path, name.clone());
+        }
+        Ok(namespace)
+    }
+
+    /// Name of the virtual file at `path`, if there is one.
+    pub fn file(&self, path: &Path) -> Option<&OsStr> {
+        self.files.get(path).map(OsString::as_os_str)
+    }
+
+    /// The entries the overlay adds to directory `path`, if it leads to any virtual file.
+    pub fn dir(&self, path: &Path) -> Option<&BTreeMap<OsString, FileType>> {
+        self.dirs.get(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Manifests for virtual files named `name`, each placed at `path` if one is given.
+    fn files(entries: &[(&str, Option<&str>)]) -> BTreeMap<OsString, Manifest> {
+        entries.iter().map(|&(name, path)| {
+            let manifest = serde_json::from_value(serde_json::json!({
+                "version": 1,
+                "path": path,
+                "chunks": [{"id": "c", "start": 0, "end": 9, "size": 10}],
+            })).unwrap();
+            (OsString::from(name), manifest)
+        }).collect()
+    }
+
+    fn error(entries: &[(&str, Option<&str>)]) -> ManifestError {
+        Namespace::new(&files(entries)).err().unwrap()
+    }
+
+    #[test]
+    fn paths_must_stay_inside_the_mount() {
+        for path in ["/etc/passwd", "../f", "a/../../f", "./f", ""] {
+            assert!(matches!(error(&[("f", Some(path))]), ManifestError::BadPath { .. }), "{:?}", path);
+        }
+        assert!(matches!(error(&[("..", None)]), ManifestError::BadPath { .. }));
+    }
+
+    #[test]
+    fn a_file_where_a_directory_is_needed_conflicts() {
+        // "a" sorts first, so the file is laid out before the directory it is in the way of.
+        let e = error(&[("a", Some("dir")), ("b", Some("dir/b"))]);
+        assert!(matches!(e, ManifestError::PathConflict { path } if path == Path::new("/dir")));
+    }
+
+    #[test]
+    fn a_file_where_a_directory_is_conflicts() {
+        let e = error(&[("a", Some("dir/a")), ("b", Some("dir"))]);
+        assert!(matches!(e, ManifestError::PathConflict { path } if path == Path::new("/dir")));
+        let e = error(&[("a", Some("x")), ("b", Some("x"))]);
+        assert!(matches!(e, ManifestError::PathConflict { path } if path == Path::new("/x")));
+    }
+
+    #[test]
+    fn nested_paths_create_directories() {
+        let namespace = Namespace::new(&files(&[
+            ("deep", Some("a/b/deep.bin")),
+            ("side", Some("a/side.bin")),
+            ("top", None),
+        ])).unwrap();
+
+        assert_eq!(namespace.file(Path::new("/a/b/deep.bin")), Some(OsStr::new("deep")));
+        assert_eq!(namespace.file(Path::new("/a/side.bin")), Some(OsStr::new("side")));
+        assert_eq!(namespace.file(Path::new("/top")), Some(OsStr::new("top")));
+        assert_eq!(namespace.file(Path::new("/deep")), None);
+        assert_eq!(namespace.file(Path::new("/a")), None);
+
+        let entries = |dir: &str| -> Vec<(String, FileType)> {
+            namespace.dir(Path::new(dir)).unwrap().iter()
+                .map(|(name, kind)| (name.to_string_lossy().into_owned(), *kind))
+                .collect()
+        };
+        assert_eq!(entries("/"), [("a".to_owned(), FileType::Directory), ("top".to_owned(), FileType::RegularFile)]);
+        assert_eq!(entries("/a"), [("b".to_owned(), FileType::Directory), ("side.bin".to_owned(), FileType::RegularFile)]);
+        assert_eq!(entries("/a/b"), [("deep.bin".to_owned(), FileType::RegularFile)]);
+        assert!(namespace.dir(Path::new("/a/b/deep.bin")).is_none());
+        assert!(namespace.dir(Path::new("/elsewhere")).is_none());
+    }
+}
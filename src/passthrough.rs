@@ -6,31 +6,64 @@
 //
 
 use std::collections::BTreeMap;
-use std::error::Error;
 use std::ffi::{CStr, CString, OsStr, OsString};
 use std::fs::{self, File};
 use std::io::{self, Read, Write, Seek, SeekFrom};
 use std::mem;
 use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::FileExt;
 use std::os::unix::io::{FromRawFd, IntoRawFd};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use fernet;
 
+use crate::crypt::Decryptor;
+use crate::fetch::Fetcher;
+use crate::handles::{HandleTable, OpenFile};
 use crate::libc_extras::libc;
 use crate::libc_wrappers;
+use crate::manifest::{Encoding, Manifest, Segment};
+use crate::namespace::Namespace;
+use crate::readahead::ReadAhead;
+use crate::store::StoreResult;
 
-use reqwest::header::{
-    AUTHORIZATION,
-    RANGE,
-};
 use fuse_mt::*;
 
 pub struct PassthroughFS {
     pub target: OsString,
+    /// Virtual files, by name.
+    pub files: BTreeMap<OsString, Manifest>,
+    /// Where the virtual files appear in the mount.
+    pub namespace: Namespace,
+    pub fetcher: Arc<Fetcher>,
+    pub readahead: ReadAhead,
+    pub decryptor: Decryptor,
+    pub owner: Owner,
+    /// State for each open handle on a virtual file.
+    pub handles: HandleTable<OpenFile>,
 }
 
-const ACCESS_TOKEN: &str = "ya29.a0AVvZVsqG09mYMuK1-7vbpAoV_JXMv-HEyidpX6fQESGce8dLaZB34qogMXUkCDS0u0TM_nYPoZw87RnZU5yFlLHntzSg3vJaXz8FVHA6EhpI9DsvX5yUG-34bp_cOE2mmScHcCNtZRv6xx0nUjAZQrQWYOIGXstthgaCgYKAd4SARESFQGbdwaIypGwR1NyL5iqDVpGvBmmoA0169";
+/// Who the virtual files (and the directories leading to them) appear to belong to, and with what
+/// permissions.
+#[derive(Clone, Copy, Debug)]
+pub struct Owner {
+    pub uid: u32,
+    pub gid: u32,
+    /// Permission bits of the virtual files; those directories get these plus search access.
+    pub perm: u16,
+}
+
+impl Owner {
+    /// The user doing the mount, with the files readable by everyone.
+    pub fn mounting_user() -> Owner {
+        Owner {
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            perm: 0o444,
+        }
+    }
+}
 
 fn mode_to_filetype(mode: libc::mode_t) -> FileType {
     match mode & libc::S_IFMT {
@@ -94,11 +127,11 @@ fn statfs_to_fuse(statfs: libc::statfs) -> Statfs {
 #[cfg(target_os = "linux")]
 fn statfs_to_fuse(statfs: libc::statfs) -> Statfs {
     Statfs {
-        blocks: statfs.f_blocks as u64,
-        bfree: statfs.f_bfree as u64,
-        bavail: statfs.f_bavail as u64,
-        files: statfs.f_files as u64,
-        ffree: statfs.f_ffree as u64,
+        blocks: statfs.f_blocks,
+        bfree: statfs.f_bfree,
+        bavail: statfs.f_bavail,
+        files: statfs.f_files,
+        ffree: statfs.f_ffree,
         bsize: statfs.f_bsize as u32,
         namelen: statfs.f_namelen as u32,
         frsize: statfs.f_frsize as u32,
@@ -112,7 +145,185 @@ impl PassthroughFS {
             .into_os_string()
     }
 
-    fn stat_real(&self, path: &Path) -> io::Result<FileAttr> {
+    /// Look up the name and manifest of the virtual file at `path`, if there is one.
+    fn virtual_file(&self, path: &Path) -> Option<(&OsStr, &Manifest)> {
+        let name = self.namespace.file(path)?;
+        Some((name, &self.files[name]))
+    }
+
+    fn is_real_dir(&self, path: &Path) -> bool {
+        libc_wrappers::lstat(self.real_path(path))
+            .is_ok_and(|stat| stat.st_mode & libc::S_IFMT == libc::S_IFDIR)
+    }
+
+    /// Whether `path` is part of the read-only overlay: a virtual file, or a directory that is only
+    /// there to lead to virtual files.
+    fn in_overlay(&self, path: &Path) -> bool {
+        self.namespace.file(path).is_some() || (self.namespace.dir(path).is_some() && !self.is_real_dir(path))
+    }
+
+    /// Whether creating, replacing or removing entry `name` of directory `parent` would change the
+    /// overlay.
+    fn touches_overlay(&self, parent: &Path, name: &OsStr) -> bool {
+        self.in_overlay(parent) || self.in_overlay(&parent.join(name))
+    }
+
+    /// Read up to `size` bytes at `offset` of the virtual file `name`.
+    ///
+    /// As with read(2), the result is short if the file ends first, and empty at or past the end.
+    pub fn read_virtual(&self, name: &OsStr, manifest: &Manifest, offset: u64, size: u32) -> StoreResult<Vec<u8>> {
+        // Reads may straddle chunk boundaries. Fetch the stored data of every piece at once, so
+        // that the pieces come in concurrently, then decode them in order and stitch them together.
+        let segments = manifest.segments(offset, size);
+        let ranges: Vec<_> = segments.iter()
+            .filter(|segment| segment.chunk.encoding != Encoding::Fernet)
+            .map(|segment| {
+                let (start, end) = segment.chunk.stored_range(segment.start, segment.end);
+                (segment.chunk, start, end)
+            })
+            .collect();
+        let mut stored = self.fetcher.read_ranges(&ranges).map_err(|e| {
+            error!("read({:?}, {:#x} @ {:#x}): {}", name, size, offset, e);
+            e
+        })?.into_iter();
+
+        let mut data = Vec::with_capacity(u64::from(size).min(manifest.size().saturating_sub(offset)) as usize);
+        for segment in &segments {
+            let stored = match segment.chunk.encoding {
+                Encoding::Fernet => Vec::new(),
+                _ => stored.next().unwrap(),
+            };
+            self.decode_segment(name, segment, stored, &mut data).map_err(|e| {
+                error!("read({:?}): chunk {} {:#x}-{:#x}: {}", name, segment.chunk.id, segment.start, segment.end, e);
+                e
+            })?;
+        }
+        Ok(data)
+    }
+
+    /// Decrypt the start of each file's first encrypted chunk, to find out early if a key is wrong.
+    pub fn check_keys(&self) -> bool {
+        let mut ok = true;
+        for (name, manifest) in &self.files {
+            if let Some(chunk) = manifest.first_encrypted() {
+                let segment = Segment { chunk, start: 0, end: 0 };
+                if let Err(e) = self.read_segment(name, &segment, &mut vec![]) {
+                    error!("{:?}: cannot decrypt chunk {}: {}", name, chunk.id, e);
+                    ok = false;
+                }
+            }
+        }
+        ok
+    }
+
+    /// Fetch one piece of a read of virtual file `name` and append it to `buf`.
+    pub fn read_segment(&self, name: &OsStr, segment: &Segment<'_>, buf: &mut Vec<u8>) -> StoreResult<()> {
+        let stored = match segment.chunk.encoding {
+            Encoding::Fernet => Vec::new(),
+            _ => {
+                let (start, end) = segment.chunk.stored_range(segment.start, segment.end);
+                self.fetcher.read_ranges(&[(segment.chunk, start, end)])?.pop().unwrap()
+            }
+        };
+        self.decode_segment(name, segment, stored, buf)
+    }
+
+    /// Append the plaintext of one piece of a read of virtual file `name` to `buf`, given its
+    /// `stored` bytes; Fernet chunks are only stored whole, so the decryptor fetches those itself.
+    fn decode_segment(&self, name: &OsStr, segment: &Segment<'_>, stored: Vec<u8>, buf: &mut Vec<u8>) -> StoreResult<()> {
+        let chunk = segment.chunk;
+        match chunk.encoding {
+            Encoding::Plain => buf.extend_from_slice(&stored),
+            Encoding::Fernet => {
+                let plaintext = self.decryptor.fernet_plaintext(&*self.fetcher.store, name, chunk)?;
+                buf.extend_from_slice(&plaintext[segment.start as usize..=segment.end as usize]);
+            }
+            Encoding::Aead => {
+                buf.extend_from_slice(&self.decryptor.open_range(name, chunk, segment.start, segment.end, &stored)?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Read from the open file `fh`, which is either a handle on a virtual file or the
+    /// descriptor of a local one.
+    fn read_handle(&self, path: &Path, fh: u64, offset: u64, size: u32) -> Result<Vec<u8>, libc::c_int> {
+        let open = match self.handles.get(fh) {
+            Some(open) => open,
+            None => {
+                // A local file, read through its descriptor.
+                let file = unsafe { UnmanagedFile::new(fh) };
+                let mut data = vec![0; size as usize];
+                return match file.read_at(&mut data, offset) {
+                    Ok(n) => {
+                        data.truncate(n);
+                        Ok(data)
+                    }
+                    Err(e) => {
+                        error!("read({:?}, {:#x} @ {:#x}): {}", path, size, offset, e);
+                        Err(e.raw_os_error().unwrap())
+                    }
+                };
+            }
+        };
+        let manifest = &self.files[&open.name];
+
+        // Start fetching what comes next before waiting on this read.
+        self.readahead.on_read(&mut open.stream.lock().unwrap(), &open.name, manifest, offset, size);
+
+        let result = self.read_virtual(&open.name, manifest, offset, size);
+        open.stats.record(result.as_ref().ok().map(Vec::len));
+        result.map_err(|e| e.errno())
+    }
+
+    /// Attributes of a virtual file, all derived from its manifest.
+    ///
+    /// There is no inode number here: fuse_mt hands those out itself, one per path, and keeps it
+    /// for as long as the kernel remembers the path.
+    pub fn virtual_attr(&self, manifest: &Manifest) -> FileAttr {
+        let size = manifest.size();
+        let mtime = manifest.mtime();
+        FileAttr {
+            size,
+            blocks: size.div_ceil(512),
+            atime: mtime,
+            mtime,
+            ctime: mtime,
+            crtime: mtime,
+            kind: FileType::RegularFile,
+            perm: self.owner.perm & !0o222,
+            nlink: 1,
+            uid: self.owner.uid,
+            gid: self.owner.gid,
+            rdev: 0,
+            flags: 0,
+        }
+    }
+
+    /// Attributes of a directory which only exists in the overlay.
+    fn overlay_dir_attr(&self) -> FileAttr {
+        // It changed when the newest of the virtual files did.
+        let mtime = self.files.values().map(Manifest::mtime).max().unwrap_or(UNIX_EPOCH);
+        let perm = self.owner.perm & !0o222;
+        FileAttr {
+            size: 4096,
+            blocks: 0,
+            atime: mtime,
+            mtime,
+            ctime: mtime,
+            crtime: mtime,
+            kind: FileType::Directory,
+            // Searchable wherever it is readable.
+            perm: perm | (perm & 0o444) >> 2,
+            nlink: 2,
+            uid: self.owner.uid,
+            gid: self.owner.gid,
+            rdev: 0,
+            flags: 0,
+        }
+    }
+
+    fn stat_real(&self, path: &Path) -> Result<FileAttr, libc::c_int> {
         let real: OsString = self.real_path(path);
         debug!("stat_real: {:?}", real);
 
@@ -121,9 +332,10 @@ impl PassthroughFS {
                 Ok(stat_to_fuse(stat))
             }
             Err(e) => {
-                let err = io::Error::from_raw_os_error(e);
-                error!("lstat({:?}): {}", path, err);
-                Err(err)
+                if e != libc::ENOENT {
+                    error!("lstat({:?}): {}", path, io::Error::from_raw_os_error(e));
+                }
+                Err(e)
             }
         }
     }
@@ -131,89 +343,6 @@ impl PassthroughFS {
 
 const TTL: Duration = Duration::from_secs(1);
 
-fn read_data_from_file(file_id: &str, start_byte: i64, end_byte: i64) -> Result<reqwest::blocking::Response, Box<dyn Error>> {
-    println!("{} start {} end", start_byte, end_byte);
-    let endpoint_url = format!("https://www.googleapis.com/drive/v3/files/{file_id}?supportsAllDrives=true&supportsTeamDrives=true&alt=media");
-    let client = reqwest::blocking::Client::new();
-    if start_byte >= 0 && end_byte >= 0 {
-        let resp = client.get(endpoint_url)
-            .header(AUTHORIZATION, format!("Bearer {ACCESS_TOKEN}"))
-            .header(RANGE, format!("bytes={start_byte}-{end_byte}"))
-            .send()?;
-        Ok(resp)
-    } else {
-        let resp = client.get(endpoint_url)
-            .header(AUTHORIZATION, format!("Bearer {ACCESS_TOKEN}"))
-            .send()?;
-        Ok(resp)
-    }
-}
-
-fn calc(offset: i64, length: u32) -> (&'static str, i64, i64) {
-    let our_map: BTreeMap<i64, &str> =
-        BTreeMap::from([(65535, "1xV10xI0QJciPZ0w06S2QoYUYDbom-m6N"),
-            (3136277525, "1Ywt29KwAoTw6XKf5edN_rr6Sc97OVakc"),
-            (4264895608, "1IxOK2bOqGRz4GwVGx7yPjnAwXq91VGiF"),
-            (5683118908, "1vJ60iOmmGPN0Nyc4IUr6YJAy2w-frWrw"),
-            (7524698365, "1RXC1L68C1z1IvD3RBp03nlqN9KCZnPM8"),
-            (9179191938, "1Cg1YS9GXBW-vAKw17Gky0Q7ak5ccAqZE"),
-            (11568039537, "1CZ6y6tU4iF9fChmjcGr3grTnTWcYm25N"),
-            (14357437778, "1aI5yJPAFvLjf2TueD0EsYGRkniICj8Ny"),
-            (17443531498, "1mu8ukspOhHBeoNV0_Ye2PAmaFGEElsr3"),
-            (18707203932, "1SXRmEzwyrRqGkyv2vNtd45AiIj2n7Xmb"),
-            (21740857834, "1i_kAThydwWQaR77yQ0jWVQgg9ab7GEs5"),
-            (24084580085, "17Chdoaxtg5-cr1fqKCocjP5EcUgyHj90"),
-            (25709868851, "1I9RnNzCTd1Yww3PW5lSADO9OkKOAJB5t"),
-            (28411019782, "1VjtTOVUmTgCMLV1qdX9l6KaslwOU1vBK"),
-            (31146299980, "1Eas0UorI384jTPO9WId_hRYI_WTfOxi0"),
-            (32616708307, "1usjXrYrP0Z_fNAn94RLcOIgVqcObH7Xj"),
-            (34795775510, "1-zPaTfMFMq5voaFHfF7ltm4te6qzzeFi"),
-            (36373250750, "1MOkkdmhcbbU2ZA7Eld2xpf-QFgcvJwBW"),
-            (38307686165, "1k0QVfXJuB3miX1OHXlfNI9FcqxAGWUup"),
-            (40566606366, "1OeUBxegGYhIJqduxCmqzOmfTGfWXi3Pz"),
-            (43109648516, "1nMwc3zinrjZZPfDN5X15f3oeC9BMfvZS"),
-            (46303643299, "1I9lBTA1HTaqRlxF9-tY2REGEIagDbpJD"),
-            (49324970002, "1r376mJJ1FCEINS78DC_wj5hDwdcilwNY"),
-            (51146338717, "1SbeMFjhdba5kQJ31yASpxic6LEmWO_Dj"),
-            (53734141657, "1iYR8jF4qmTw2DvA7tnQMUwjFM7Gls4Iw"),
-            (56840465632, "1BnMfN-ASfROnXiVxJJvFTDUMgOH7yMo_"),
-            (57916633250, "1o36WXUYsOYE2rvG6Oiv95DlPpYj1sK8m"),
-            (60850437032, "1izQXyjH06P_gY9QQhe2sysvRErp_E3YP"),
-            (63182086659, "1PzvsvvcEm1FGcksPA73dacOF0d6ZpIBu"),
-            (64894284328, "1wdcAtZIuiHavkqWgVhm3RcqaPqDFllVA"),
-            (67145810297, "1kz1JJBLwwSBb5cTI6hzyF2FdbM41Hrxc"),
-            (69655104876, "1ZhybvmT1CuH2lmh9-ELyKxt9sHIwSXzr"),
-            (72687779417, "1_pMK7M_kSFeSYEB0SpZ78kBoIPndj7oX"),
-            (75313290690, "1QVY_dIPC8pXp3dv4dyIUP_-y92S6lj7H"),
-            (78419280338, "10JqukZYupv88e6oQFaG59jKB6URyP-M5"),
-            (80480908546, "16DbRqXvSvCIaFQ8ogmmfGxEFLthIU31x"),
-            (83388003875, "177JNnu7vaCXD-lxWQ11rsxexyLllh_ZX"),
-            (85214586791, "1HbPsOwbUBEeWNUUJkbFQ2_xJ5ChXwPFw"),
-            (86895533949, "1tAKEtny2zB7oBQrCTReQ3CfZ2Y0UksU_"),
-            (88467589447, "1tfsrg1c0EVcLt70GSppDQxolWVVgz-Go"),
-            (90524897624, "1Ref5HYXdeplmYlyMTAw1dfe9hVqcVQ9T"),
-            (92701391675, "1LjDwNHxzWMUWoKNxpT0kXZpHbnRWp7mN"),
-            (95705418734, "1JU601nRmgfqOkkuunZD0c6W4XvBsAGLx"),
-            (98283004808, "18qEFYin7Ul8VHgDqf-UpZk4XZBBymAkF"),
-            (100655737957, "1ycPtJi1WI8etHx_stsgl-sDTSZDjZHrb"),
-            (102157030373, "1_zs0WIKPar1gAoAQ0mitdiTZWrczcSyi"),
-            (105252025064, "1goafZpkSCIZf-gfoA9JIaHmhze7Ip4MQ"),
-            (108035212644, "1H2CRA3pCJ7VbK9741meArYzEQ3q2fnXF"),
-            (108797856195, "1dhFt7Lxxs0Y8nTQeSmlMs-J7CGG6nbGZ"), ]
-        );
-    let mut current_start_byte = 0;
-    for (end_byte, file_id) in &our_map {
-        // println!("{} end_byte", end_byte);
-        let x = offset;
-        let y = offset + length as i64 - 1;
-        if current_start_byte <= x && y <= *end_byte {
-            return (file_id, x - current_start_byte, y - current_start_byte);
-        }
-        current_start_byte = *end_byte + 1
-    }
-    return ("", 0, 0);
-}
-
 impl FilesystemMT for PassthroughFS {
     fn init(&self, _req: RequestInfo) -> ResultEmpty {
         debug!("init");
@@ -222,46 +351,31 @@ impl FilesystemMT for PassthroughFS {
 
     fn destroy(&self) {
         debug!("destroy");
+        let open = self.handles.len();
+        if open > 0 {
+            warn!("{} handles on virtual files were never released", open);
+        }
+        let stats = self.fetcher.cache.stats();
+        info!("block cache: {} hits, {} misses, {} blocks ({} bytes) cached",
+              stats.hits, stats.misses, stats.blocks, stats.bytes);
+        if let Some(disk_cache) = &self.fetcher.disk_cache {
+            if let Err(e) = disk_cache.flush() {
+                error!("disk cache: {}", e);
+            }
+        }
     }
 
-    fn getattr(&self, _req: RequestInfo, path: &Path, fh: Option<u64>) -> ResultEntry {
+    fn getattr(&self, _req: RequestInfo, path: &Path, _fh: Option<u64>) -> ResultEntry {
         debug!("getattr: {:?}", path);
-        if path.as_os_str() == "/" {
-            const HELLO_DIR_ATTR: FileAttr = FileAttr {
-                size: 4096,
-                blocks: 0,
-                atime: UNIX_EPOCH, // 1970-01-01 00:00:00
-                mtime: UNIX_EPOCH,
-                ctime: UNIX_EPOCH,
-                crtime: UNIX_EPOCH,
-                kind: FileType::Directory,
-                perm: 0o755,
-                nlink: 2,
-                uid: 501,
-                gid: 20,
-                rdev: 0,
-                flags: 0,
-            };
-            Ok((TTL, HELLO_DIR_ATTR))
-        } else {
-            const HELLO_TXT_ATTR: FileAttr = FileAttr {
-                // ino: 33188,
-                size: 108830059313,
-                blocks: 50000,
-                atime: UNIX_EPOCH, // 1970-01-01 00:00:00
-                mtime: UNIX_EPOCH,
-                ctime: UNIX_EPOCH,
-                crtime: UNIX_EPOCH,
-                kind: FileType::RegularFile,
-                perm: 0o644,
-                nlink: 1,
-                uid: 1001,
-                gid: 1002,
-                rdev: 0,
-                flags: 0,
-                // blksize: 512,
-            };
-            Ok((TTL, HELLO_TXT_ATTR))
+        if let Some((_, manifest)) = self.virtual_file(path) {
+            return Ok((TTL, self.virtual_attr(manifest)));
+        }
+        let overlay_dir = self.namespace.dir(path).is_some();
+        match self.stat_real(path) {
+            Ok(attr) if !overlay_dir || attr.kind == FileType::Directory => Ok((TTL, attr)),
+            // Nothing local, or a local file where the overlay needs a directory.
+            _ if overlay_dir => Ok((TTL, self.overlay_dir_attr())),
+            result => result.map(|attr| (TTL, attr)),
         }
     }
 
@@ -270,6 +384,8 @@ impl FilesystemMT for PassthroughFS {
         debug!("opendir: {:?} (flags = {:#o})", real, _flags);
         match libc_wrappers::opendir(real) {
             Ok(fh) => Ok((fh, 0)),
+            // Only the overlay has it; readdir lists it from that alone.
+            Err(_) if self.in_overlay(path) => Ok((0, 0)),
             Err(e) => {
                 let ioerr = io::Error::from_raw_os_error(e);
                 error!("opendir({:?}): {}", path, ioerr);
@@ -280,16 +396,32 @@ impl FilesystemMT for PassthroughFS {
 
     fn releasedir(&self, _req: RequestInfo, path: &Path, fh: u64, _flags: u32) -> ResultEmpty {
         debug!("releasedir: {:?}", path);
+        if fh == 0 {
+            return Ok(());
+        }
         libc_wrappers::closedir(fh)
     }
 
     fn readdir(&self, _req: RequestInfo, path: &Path, fh: u64) -> ResultReaddir {
         debug!("readdir: {:?}", path);
         let mut entries: Vec<DirectoryEntry> = vec![];
+        let overlay = self.namespace.dir(path);
 
         if fh == 0 {
-            error!("readdir: missing fh");
-            return Err(libc::EINVAL);
+            let overlay = match overlay {
+                Some(overlay) => overlay,
+                None => {
+                    error!("readdir: missing fh");
+                    return Err(libc::EINVAL);
+                }
+            };
+            for name in [".", ".."] {
+                entries.push(DirectoryEntry { name: name.into(), kind: FileType::Directory });
+            }
+            for (name, kind) in overlay {
+                entries.push(DirectoryEntry { name: name.clone(), kind: *kind });
+            }
+            return Ok(entries);
         }
 
         loop {
@@ -297,6 +429,10 @@ impl FilesystemMT for PassthroughFS {
                 Ok(Some(entry)) => {
                     let name_c = unsafe { CStr::from_ptr(entry.d_name.as_ptr()) };
                     let name = OsStr::from_bytes(name_c.to_bytes()).to_owned();
+                    if overlay.is_some_and(|overlay| overlay.contains_key(&name)) {
+                        // Listed below, as what the overlay puts there.
+                        continue;
+                    }
 
                     let filetype = match entry.d_type {
                         libc::DT_DIR => FileType::Directory,
@@ -336,15 +472,28 @@ impl FilesystemMT for PassthroughFS {
             }
         }
 
+        for (name, kind) in overlay.into_iter().flatten() {
+            entries.push(DirectoryEntry { name: name.clone(), kind: *kind });
+        }
         Ok(entries)
     }
 
     fn open(&self, _req: RequestInfo, path: &Path, flags: u32) -> ResultOpen {
         debug!("open: {:?} flags={:#x}", path, flags);
 
+        if let Some((name, _)) = self.virtual_file(path) {
+            let mode = flags as libc::c_int;
+            if mode & libc::O_ACCMODE != libc::O_RDONLY || mode & libc::O_TRUNC != 0 {
+                return Err(libc::EROFS);
+            }
+            let fh = self.handles.insert(OpenFile::new(name.to_owned()));
+            debug!("open: {:?} is handle {:#x}", path, fh);
+            return Ok((fh, 0));
+        }
+
         let real = self.real_path(path);
         match libc_wrappers::open(real, flags as libc::c_int) {
-            Ok(fh) => Ok((0, 0)),
+            Ok(fh) => Ok((fh, 0)),
             Err(e) => {
                 error!("open({:?}): {}", path, io::Error::from_raw_os_error(e));
                 Err(e)
@@ -354,58 +503,45 @@ impl FilesystemMT for PassthroughFS {
 
     fn release(&self, _req: RequestInfo, path: &Path, fh: u64, _flags: u32, _lock_owner: u64, _flush: bool) -> ResultEmpty {
         debug!("release: {:?}", path);
+        if let Some(open) = self.handles.remove(fh) {
+            let stats = &open.stats;
+            debug!("release: {:?}: {} reads, {} bytes, {} errors", open.name,
+                   stats.reads.load(Ordering::Relaxed), stats.bytes.load(Ordering::Relaxed),
+                   stats.errors.load(Ordering::Relaxed));
+            return Ok(());
+        }
         libc_wrappers::close(fh)
     }
 
     fn read(&self, _req: RequestInfo, path: &Path, fh: u64, offset: u64, size: u32, callback: impl FnOnce(ResultSlice<'_>) -> CallbackResult) -> CallbackResult {
         debug!("read: {:?} {:#x} @ {:#x}", path, size, offset);
-        let (file_id, start_byte, end_byte) = calc(offset as i64, size);
-        if offset < 64 * 1024 {
-            match read_data_from_file(file_id, -1, -1) {
-                Ok(data) => {
-                    let key = "E-bxU5geNyrojsSg2mqn5Yv1_veAczf0xaffrFJBSjk=";
-                    let fernet_obj = fernet::Fernet::new(&key).unwrap();
-                    let decrypted_data = fernet_obj.decrypt(&data.text().unwrap()).unwrap();
-                    callback(Ok(&decrypted_data.as_slice()[offset as usize..(offset as i64 + size as i64 - 1) as usize]))
-                }
-                Err(e) => { callback(Err(0)) }
-            }
-        } else {
-            match read_data_from_file(file_id, start_byte as i64, end_byte as i64) {
-                Ok(data) => {
-                    // println!("{}", &data.bytes().unwrap().len());
-                    // reply.data(&data.text().unwrap().as_bytes()[..size as usize]);
-                    let resp_data = &data.bytes().unwrap();
-                    callback(Ok(&resp_data))
-                    // println!("{:x?}", resp_data)
-                }
-
-                Err(e) => { callback(Err(0)) }
-            }
+        match self.read_handle(path, fh, offset, size) {
+            Ok(data) => callback(Ok(&data)),
+            Err(e) => callback(Err(e)),
         }
     }
 
     fn write(&self, _req: RequestInfo, path: &Path, fh: u64, offset: u64, data: Vec<u8>, _flags: u32) -> ResultWrite {
         debug!("write: {:?} {:#x} @ {:#x}", path, data.len(), offset);
-        let mut file = unsafe { UnmanagedFile::new(fh) };
+        if self.handles.get(fh).is_some() {
+            // Virtual files are only ever opened for reading.
+            return Err(libc::EBADF);
+        }
+        let file = unsafe { UnmanagedFile::new(fh) };
 
-        if let Err(e) = file.seek(SeekFrom::Start(offset)) {
-            error!("seek({:?}, {}): {}", path, offset, e);
+        if let Err(e) = file.write_all_at(&data, offset) {
+            error!("write {:?}, {:#x} @ {:#x}: {}", path, data.len(), offset, e);
             return Err(e.raw_os_error().unwrap());
         }
-        let nwritten: u32 = match file.write(&data) {
-            Ok(n) => n as u32,
-            Err(e) => {
-                error!("write {:?}, {:#x} @ {:#x}: {}", path, data.len(), offset, e);
-                return Err(e.raw_os_error().unwrap());
-            }
-        };
 
-        Ok(nwritten)
+        Ok(data.len() as u32)
     }
 
     fn flush(&self, _req: RequestInfo, path: &Path, fh: u64, _lock_owner: u64) -> ResultEmpty {
         debug!("flush: {:?}", path);
+        if self.handles.get(fh).is_some() {
+            return Ok(());
+        }
         let mut file = unsafe { UnmanagedFile::new(fh) };
 
         if let Err(e) = file.flush() {
@@ -418,6 +554,9 @@ impl FilesystemMT for PassthroughFS {
 
     fn fsync(&self, _req: RequestInfo, path: &Path, fh: u64, datasync: bool) -> ResultEmpty {
         debug!("fsync: {:?}, data={:?}", path, datasync);
+        if self.handles.get(fh).is_some() {
+            return Ok(());
+        }
         let file = unsafe { UnmanagedFile::new(fh) };
 
         if let Err(e) = if datasync {
@@ -435,6 +574,10 @@ impl FilesystemMT for PassthroughFS {
     fn chmod(&self, _req: RequestInfo, path: &Path, fh: Option<u64>, mode: u32) -> ResultEmpty {
         debug!("chmod: {:?} to {:#o}", path, mode);
 
+        if self.in_overlay(path) {
+            return Err(libc::EROFS);
+        }
+
         let result = if let Some(fh) = fh {
             unsafe { libc::fchmod(fh as libc::c_int, mode as libc::mode_t) }
         } else {
@@ -455,11 +598,15 @@ impl FilesystemMT for PassthroughFS {
     }
 
     fn chown(&self, _req: RequestInfo, path: &Path, fh: Option<u64>, uid: Option<u32>, gid: Option<u32>) -> ResultEmpty {
-        let uid = uid.unwrap_or(::std::u32::MAX);   // docs say "-1", but uid_t is unsigned
-        let gid = gid.unwrap_or(::std::u32::MAX);
+        let uid = uid.unwrap_or(u32::MAX);   // docs say "-1", but uid_t is unsigned
+        let gid = gid.unwrap_or(u32::MAX);
         // ditto for gid_t
         debug!("chown: {:?} to {}:{}", path, uid, gid);
 
+        if self.in_overlay(path) {
+            return Err(libc::EROFS);
+        }
+
         let result = if let Some(fd) = fh {
             unsafe { libc::fchown(fd as libc::c_int, uid, gid) }
         } else {
@@ -482,6 +629,10 @@ impl FilesystemMT for PassthroughFS {
     fn truncate(&self, _req: RequestInfo, path: &Path, fh: Option<u64>, size: u64) -> ResultEmpty {
         debug!("truncate: {:?} to {:#x}", path, size);
 
+        if self.in_overlay(path) {
+            return Err(libc::EROFS);
+        }
+
         let result = if let Some(fd) = fh {
             unsafe { libc::ftruncate64(fd as libc::c_int, size as i64) }
         } else {
@@ -504,6 +655,10 @@ impl FilesystemMT for PassthroughFS {
     fn utimens(&self, _req: RequestInfo, path: &Path, fh: Option<u64>, atime: Option<SystemTime>, mtime: Option<SystemTime>) -> ResultEmpty {
         debug!("utimens: {:?}: {:?}, {:?}", path, atime, mtime);
 
+        if self.in_overlay(path) {
+            return Err(libc::EROFS);
+        }
+
         let systemtime_to_libc = |time: Option<SystemTime>| -> libc::timespec {
             if let Some(time) = time {
                 let (secs, nanos) = match time.duration_since(SystemTime::UNIX_EPOCH) {
@@ -550,6 +705,10 @@ impl FilesystemMT for PassthroughFS {
     fn readlink(&self, _req: RequestInfo, path: &Path) -> ResultData {
         debug!("readlink: {:?}", path);
 
+        if self.in_overlay(path) {
+            return Err(libc::EINVAL);
+        }
+
         let real = self.real_path(path);
         match ::std::fs::read_link(real) {
             Ok(target) => Ok(target.into_os_string().into_vec()),
@@ -560,7 +719,8 @@ impl FilesystemMT for PassthroughFS {
     fn statfs(&self, _req: RequestInfo, path: &Path) -> ResultStatfs {
         debug!("statfs: {:?}", path);
 
-        let real = self.real_path(path);
+        // Overlay paths don't exist underneath; report the filesystem they are mounted over.
+        let real = self.real_path(if self.in_overlay(path) { Path::new("/") } else { path });
         let mut buf: libc::statfs = unsafe { ::std::mem::zeroed() };
         let result = unsafe {
             let path_c = CString::from_vec_unchecked(real.into_vec());
@@ -578,6 +738,10 @@ impl FilesystemMT for PassthroughFS {
 
     fn fsyncdir(&self, _req: RequestInfo, path: &Path, fh: u64, datasync: bool) -> ResultEmpty {
         debug!("fsyncdir: {:?} (datasync = {:?})", path, datasync);
+        if fh == 0 {
+            // Only the overlay has it, and there is nothing to sync.
+            return Ok(());
+        }
 
         // TODO: what does datasync mean with regards to a directory handle?
         let result = unsafe { libc::fsync(fh as libc::c_int) };
@@ -593,6 +757,10 @@ impl FilesystemMT for PassthroughFS {
     fn mknod(&self, _req: RequestInfo, parent_path: &Path, name: &OsStr, mode: u32, rdev: u32) -> ResultEntry {
         debug!("mknod: {:?}/{:?} (mode={:#o}, rdev={})", parent_path, name, mode, rdev);
 
+        if self.touches_overlay(parent_path, name) {
+            return Err(libc::EROFS);
+        }
+
         let real = PathBuf::from(self.real_path(parent_path)).join(name);
         let result = unsafe {
             let path_c = CString::from_vec_unchecked(real.as_os_str().as_bytes().to_vec());
@@ -614,6 +782,10 @@ impl FilesystemMT for PassthroughFS {
     fn mkdir(&self, _req: RequestInfo, parent_path: &Path, name: &OsStr, mode: u32) -> ResultEntry {
         debug!("mkdir {:?}/{:?} (mode={:#o})", parent_path, name, mode);
 
+        if self.touches_overlay(parent_path, name) {
+            return Err(libc::EROFS);
+        }
+
         let real = PathBuf::from(self.real_path(parent_path)).join(name);
         let result = unsafe {
             let path_c = CString::from_vec_unchecked(real.as_os_str().as_bytes().to_vec());
@@ -638,6 +810,10 @@ impl FilesystemMT for PassthroughFS {
     fn unlink(&self, _req: RequestInfo, parent_path: &Path, name: &OsStr) -> ResultEmpty {
         debug!("unlink {:?}/{:?}", parent_path, name);
 
+        if self.touches_overlay(parent_path, name) {
+            return Err(libc::EROFS);
+        }
+
         let real = PathBuf::from(self.real_path(parent_path)).join(name);
         fs::remove_file(&real)
             .map_err(|ioerr| {
@@ -649,6 +825,10 @@ impl FilesystemMT for PassthroughFS {
     fn rmdir(&self, _req: RequestInfo, parent_path: &Path, name: &OsStr) -> ResultEmpty {
         debug!("rmdir: {:?}/{:?}", parent_path, name);
 
+        if self.touches_overlay(parent_path, name) {
+            return Err(libc::EROFS);
+        }
+
         let real = PathBuf::from(self.real_path(parent_path)).join(name);
         fs::remove_dir(&real)
             .map_err(|ioerr| {
@@ -660,6 +840,10 @@ impl FilesystemMT for PassthroughFS {
     fn symlink(&self, _req: RequestInfo, parent_path: &Path, name: &OsStr, target: &Path) -> ResultEntry {
         debug!("symlink: {:?}/{:?} -> {:?}", parent_path, name, target);
 
+        if self.touches_overlay(parent_path, name) {
+            return Err(libc::EROFS);
+        }
+
         let real = PathBuf::from(self.real_path(parent_path)).join(name);
         match ::std::os::unix::fs::symlink(target, &real) {
             Ok(()) => {
@@ -681,6 +865,10 @@ impl FilesystemMT for PassthroughFS {
     fn rename(&self, _req: RequestInfo, parent_path: &Path, name: &OsStr, newparent_path: &Path, newname: &OsStr) -> ResultEmpty {
         debug!("rename: {:?}/{:?} -> {:?}/{:?}", parent_path, name, newparent_path, newname);
 
+        if self.touches_overlay(parent_path, name) || self.touches_overlay(newparent_path, newname) {
+            return Err(libc::EROFS);
+        }
+
         let real = PathBuf::from(self.real_path(parent_path)).join(name);
         let newreal = PathBuf::from(self.real_path(newparent_path)).join(newname);
         fs::rename(&real, &newreal)
@@ -693,6 +881,10 @@ impl FilesystemMT for PassthroughFS {
     fn link(&self, _req: RequestInfo, path: &Path, newparent: &Path, newname: &OsStr) -> ResultEntry {
         debug!("link: {:?} -> {:?}/{:?}", path, newparent, newname);
 
+        if self.in_overlay(path) || self.touches_overlay(newparent, newname) {
+            return Err(libc::EROFS);
+        }
+
         let real = self.real_path(path);
         let newreal = PathBuf::from(self.real_path(newparent)).join(newname);
         match fs::hard_link(&real, &newreal) {
@@ -715,6 +907,10 @@ impl FilesystemMT for PassthroughFS {
     fn create(&self, _req: RequestInfo, parent: &Path, name: &OsStr, mode: u32, flags: u32) -> ResultCreate {
         debug!("create: {:?}/{:?} (mode={:#o}, flags={:#x})", parent, name, mode, flags);
 
+        if self.touches_overlay(parent, name) {
+            return Err(libc::EROFS);
+        }
+
         let real = PathBuf::from(self.real_path(parent)).join(name);
         let fd = unsafe {
             let real_c = CString::from_vec_unchecked(real.clone().into_os_string().into_vec());
@@ -744,12 +940,16 @@ impl FilesystemMT for PassthroughFS {
     fn listxattr(&self, _req: RequestInfo, path: &Path, size: u32) -> ResultXattr {
         debug!("listxattr: {:?}", path);
 
+        if self.in_overlay(path) {
+            return Ok(if size > 0 { Xattr::Data(Vec::new()) } else { Xattr::Size(0) });
+        }
+
         let real = self.real_path(path);
 
         if size > 0 {
             let mut data = Vec::<u8>::with_capacity(size as usize);
             let nread = libc_wrappers::llistxattr(
-                real, unsafe { mem::transmute(data.spare_capacity_mut()) })?;
+                real, unsafe { mem::transmute::<&mut [mem::MaybeUninit<u8>], &mut [u8]>(data.spare_capacity_mut()) })?;
             unsafe { data.set_len(nread) };
             Ok(Xattr::Data(data))
         } else {
@@ -761,12 +961,16 @@ impl FilesystemMT for PassthroughFS {
     fn getxattr(&self, _req: RequestInfo, path: &Path, name: &OsStr, size: u32) -> ResultXattr {
         debug!("getxattr: {:?} {:?} {}", path, name, size);
 
+        if self.in_overlay(path) {
+            return Err(libc::ENOATTR);
+        }
+
         let real = self.real_path(path);
 
         if size > 0 {
             let mut data = Vec::<u8>::with_capacity(size as usize);
             let nread = libc_wrappers::lgetxattr(
-                real, name.to_owned(), unsafe { mem::transmute(data.spare_capacity_mut()) })?;
+                real, name.to_owned(), unsafe { mem::transmute::<&mut [mem::MaybeUninit<u8>], &mut [u8]>(data.spare_capacity_mut()) })?;
             unsafe { data.set_len(nread) };
             Ok(Xattr::Data(data))
         } else {
@@ -777,12 +981,22 @@ impl FilesystemMT for PassthroughFS {
 
     fn setxattr(&self, _req: RequestInfo, path: &Path, name: &OsStr, value: &[u8], flags: u32, position: u32) -> ResultEmpty {
         debug!("setxattr: {:?} {:?} {} bytes, flags = {:#x}, pos = {}", path, name, value.len(), flags, position);
+
+        if self.in_overlay(path) {
+            return Err(libc::EROFS);
+        }
+
         let real = self.real_path(path);
         libc_wrappers::lsetxattr(real, name.to_owned(), value, flags, position)
     }
 
     fn removexattr(&self, _req: RequestInfo, path: &Path, name: &OsStr) -> ResultEmpty {
         debug!("removexattr: {:?} {:?}", path, name);
+
+        if self.in_overlay(path) {
+            return Err(libc::EROFS);
+        }
+
         let real = self.real_path(path);
         libc_wrappers::lremovexattr(real, name.to_owned())
     }
@@ -821,13 +1035,30 @@ impl UnmanagedFile {
     fn sync_data(&self) -> io::Result<()> {
         self.inner.as_ref().unwrap().sync_data()
     }
+    /// Like pread(2), but only short at the end of the file.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let file = self.inner.as_ref().unwrap();
+        let mut filled = 0;
+        while filled < buf.len() {
+            match file.read_at(&mut buf[filled..], offset + filled as u64) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(filled)
+    }
+    fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.inner.as_ref().unwrap().write_all_at(buf, offset)
+    }
 }
 
 impl Drop for UnmanagedFile {
     fn drop(&mut self) {
         // Release control of the file descriptor so it is not closed.
         let file = self.inner.take().unwrap();
-        file.into_raw_fd();
+        let _ = file.into_raw_fd();
     }
 }
 
@@ -854,3 +1085,293 @@ impl Seek for UnmanagedFile {
         self.inner.as_ref().unwrap().seek(pos)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::BlockCache;
+    use crate::crypt::{Decryptor, KeySource};
+    use crate::fetch::{FetchConfig, FetchPool};
+    use crate::readahead::ReadAheadConfig;
+    use crate::store::CountingStore;
+    use std::thread;
+
+    const HEADER: usize = 100;
+    const BODY: usize = 150;
+    const TAIL: usize = 50;
+    const SIZE: usize = HEADER + BODY + TAIL;
+
+    /// Fetch everything on the reading thread, one request per run of blocks.
+    const SERIAL: FetchConfig = FetchConfig { request_size: 1 << 20, workers: 0 };
+
+    fn fixture(dir: &Path) -> (PassthroughFS, Manifest, Vec<u8>) {
+        fixture_with(dir, Arc::new(CountingStore::new(dir)), 1 << 20, SERIAL)
+    }
+
+    /// A virtual file "f" made of a Fernet header chunk and two plain chunks in a local directory,
+    /// along with the plaintext it should read back as.
+    fn fixture_with(dir: &Path, store: Arc<CountingStore>, cache_capacity: usize, fetch: FetchConfig) -> (PassthroughFS, Manifest, Vec<u8>) {
+        let plaintext: Vec<u8> = (0..SIZE).map(|i| (i * 7 % 251) as u8).collect();
+        let key = fernet::Fernet::generate_key();
+        let token = fernet::Fernet::new(&key).unwrap().encrypt(&plaintext[..HEADER]);
+        fs::write(dir.join("header"), token).unwrap();
+        fs::write(dir.join("body"), &plaintext[HEADER..HEADER + BODY]).unwrap();
+        fs::write(dir.join("tail"), &plaintext[HEADER + BODY..]).unwrap();
+
+        let manifest: Manifest = serde_json::from_value(serde_json::json!({
+            "version": 1,
+            "chunks": [
+                {"id": "header", "start": 0, "end": HEADER - 1, "size": HEADER, "encoding": "fernet"},
+                {"id": "body", "start": HEADER, "end": HEADER + BODY - 1, "size": BODY},
+                {"id": "tail", "start": HEADER + BODY, "end": SIZE - 1, "size": TAIL},
+            ],
+        })).unwrap();
+        manifest.validate().unwrap();
+
+        let mut keys = BTreeMap::new();
+        keys.insert(OsString::from("f"), KeySource::Literal(key.into()).keys(None).unwrap());
+        // Small blocks, so that reads also cross block boundaries within a chunk.
+        let fetcher = Arc::new(Fetcher::new(
+            store,
+            BlockCache::new(32, cache_capacity),
+            None,
+            FetchPool::new(fetch),
+        ));
+        let readahead = ReadAhead::new(Arc::clone(&fetcher), ReadAheadConfig {
+            initial_window: 0,
+            max_window: 0,
+            max_in_flight: 0,
+            workers: 0,
+        });
+        let files = BTreeMap::from([(OsString::from("f"), manifest.clone())]);
+        let filesystem = PassthroughFS {
+            target: dir.as_os_str().to_owned(),
+            namespace: Namespace::new(&files).unwrap(),
+            files,
+            fetcher,
+            readahead,
+            decryptor: Decryptor::new(keys),
+            owner: Owner { uid: 1234, gid: 5678, perm: 0o440 },
+            handles: HandleTable::new(),
+        };
+        (filesystem, manifest, plaintext)
+    }
+
+    /// Details of the calling process, which the filesystem ignores.
+    fn req() -> RequestInfo {
+        RequestInfo { unique: 0, uid: 0, gid: 0, pid: 0 }
+    }
+
+    fn read(filesystem: &PassthroughFS, manifest: &Manifest, offset: u64, size: u32) -> Vec<u8> {
+        filesystem.read_virtual(OsStr::new("f"), manifest, offset, size).unwrap()
+    }
+
+    #[test]
+    fn whole_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let (filesystem, manifest, plaintext) = fixture(dir.path());
+        assert_eq!(read(&filesystem, &manifest, 0, SIZE as u32), plaintext);
+    }
+
+    #[test]
+    fn header_region_is_exact() {
+        let dir = tempfile::tempdir().unwrap();
+        let (filesystem, manifest, plaintext) = fixture(dir.path());
+        assert_eq!(read(&filesystem, &manifest, 0, HEADER as u32), &plaintext[..HEADER]);
+        assert_eq!(read(&filesystem, &manifest, 10, 5), &plaintext[10..15]);
+        assert_eq!(read(&filesystem, &manifest, HEADER as u64 - 1, 1), &plaintext[HEADER - 1..HEADER]);
+    }
+
+    #[test]
+    fn reads_across_chunk_boundaries() {
+        let dir = tempfile::tempdir().unwrap();
+        let (filesystem, manifest, plaintext) = fixture(dir.path());
+        for boundary in [HEADER, HEADER + BODY] {
+            for before in 0..3 {
+                for after in 0..3 {
+                    let offset = boundary - before;
+                    let size = before + after;
+                    assert_eq!(read(&filesystem, &manifest, offset as u64, size as u32),
+                               &plaintext[offset..offset + size],
+                               "{} bytes at {}", size, offset);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn short_read_at_eof() {
+        let dir = tempfile::tempdir().unwrap();
+        let (filesystem, manifest, plaintext) = fixture(dir.path());
+        assert_eq!(read(&filesystem, &manifest, SIZE as u64 - 10, 4096), &plaintext[SIZE - 10..]);
+        assert_eq!(read(&filesystem, &manifest, 0, u32::MAX), plaintext);
+    }
+
+    #[test]
+    fn empty_read_at_and_past_eof() {
+        let dir = tempfile::tempdir().unwrap();
+        let (filesystem, manifest, _) = fixture(dir.path());
+        assert!(read(&filesystem, &manifest, SIZE as u64, 4096).is_empty());
+        assert!(read(&filesystem, &manifest, SIZE as u64 + 1000, 4096).is_empty());
+        assert!(read(&filesystem, &manifest, u64::MAX, 4096).is_empty());
+        assert!(read(&filesystem, &manifest, 0, 0).is_empty());
+    }
+
+    #[test]
+    fn every_offset_and_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let (filesystem, manifest, plaintext) = fixture(dir.path());
+        for offset in 0..=SIZE + 5 {
+            for size in [1, 31, 32, 33, 99, 100, 101, 4096] {
+                let end = (offset + size).min(SIZE);
+                let expected = plaintext.get(offset..end).unwrap_or(&[]);
+                assert_eq!(read(&filesystem, &manifest, offset as u64, size as u32), expected,
+                           "{} bytes at {}", size, offset);
+            }
+        }
+    }
+
+    #[test]
+    fn attributes_come_from_the_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let (filesystem, mut manifest, _) = fixture(dir.path());
+        manifest.modified = Some(1_600_000_000);
+        let attr = filesystem.virtual_attr(&manifest);
+        assert_eq!(attr.size, SIZE as u64);
+        assert_eq!(attr.blocks, 1);
+        assert_eq!(attr.mtime, UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+        assert_eq!(attr.ctime, attr.mtime);
+        assert_eq!((attr.uid, attr.gid, attr.perm), (1234, 5678, 0o440));
+    }
+
+    #[test]
+    fn fuse_threads_read_concurrently() {
+        // One thread per block of the file: the Fernet header, five blocks of body and two of tail.
+        const THREADS: usize = 8;
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(CountingStore::new(dir.path()).together(THREADS));
+        // With nothing cached, every read waits on the backend.
+        let (filesystem, _, plaintext) = fixture_with(dir.path(), Arc::clone(&store), 0, SERIAL);
+        let path = Path::new("/f");
+
+        // Each thread opens the file, reads its block and closes it again, as FUSE threads
+        // serving different readers would.
+        thread::scope(|scope| {
+            for t in 0..THREADS {
+                let (filesystem, plaintext) = (&filesystem, &plaintext);
+                scope.spawn(move || {
+                    // Blocks are aligned to the start of each chunk.
+                    let (offset, size) = match t {
+                        0 => (0, HEADER),
+                        1..=5 => (HEADER + (t - 1) * 32, 16),
+                        _ => (HEADER + BODY + (t - 6) * 32, 16),
+                    };
+                    let (fh, _) = filesystem.open(req(), path, libc::O_RDONLY as u32).unwrap();
+                    assert_eq!(filesystem.read_handle(path, fh, offset as u64, size as u32).unwrap(),
+                               &plaintext[offset..offset + size]);
+                    filesystem.release(req(), path, fh, 0, 0, false).unwrap();
+                });
+            }
+        });
+        assert_eq!((store.reads(), store.most_reading()), (THREADS, THREADS));
+        assert_eq!(filesystem.handles.len(), 0);
+    }
+
+    #[test]
+    fn large_reads_fetch_pieces_concurrently() {
+        // The plain chunks are 5 and 2 blocks long; fetched a block at a time, that's 7 requests.
+        let pieces = FetchConfig { request_size: 32, workers: 8 };
+        let counted_read = |together, fetch| {
+            let dir = tempfile::tempdir().unwrap();
+            let store = Arc::new(CountingStore::new(dir.path()).together(together));
+            let (filesystem, manifest, plaintext) = fixture_with(dir.path(), Arc::clone(&store), 0, fetch);
+            assert_eq!(read(&filesystem, &manifest, HEADER as u64, (BODY + TAIL) as u32), &plaintext[HEADER..]);
+            (store.reads(), store.most_reading())
+        };
+        assert_eq!(counted_read(0, FetchConfig { workers: 0, ..pieces }), (7, 1));
+        assert_eq!(counted_read(7, pieces), (7, 7));
+    }
+
+    #[test]
+    fn release_drops_handle_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let (filesystem, _, _) = fixture(dir.path());
+        let (first, _) = filesystem.open(req(), Path::new("/f"), libc::O_RDONLY as u32).unwrap();
+        let (second, _) = filesystem.open(req(), Path::new("/f"), libc::O_RDONLY as u32).unwrap();
+        assert_ne!(first, second);
+        assert!(first >= crate::handles::FIRST_HANDLE);
+        assert_eq!(filesystem.handles.len(), 2);
+
+        filesystem.release(req(), Path::new("/f"), first, 0, 0, false).unwrap();
+        assert!(filesystem.handles.get(first).is_none());
+        assert!(filesystem.handles.get(second).is_some());
+        filesystem.release(req(), Path::new("/f"), second, 0, 0, false).unwrap();
+        assert_eq!(filesystem.handles.len(), 0);
+    }
+
+    #[test]
+    fn local_files_sit_beside_virtual_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let (filesystem, _, _) = fixture(dir.path());
+        let root = Path::new("/");
+
+        let (fh, _) = filesystem.opendir(req(), root, 0).unwrap();
+        let mut names: Vec<_> = filesystem.readdir(req(), root, fh).unwrap().into_iter().map(|e| e.name).collect();
+        filesystem.releasedir(req(), root, fh, 0).unwrap();
+        names.sort();
+        assert_eq!(names, [".", "..", "body", "f", "header", "tail"]);
+
+        // Local files are passed through, writes and all.
+        let (_, attr) = filesystem.getattr(req(), Path::new("/body"), None).unwrap();
+        assert_eq!(attr.size, BODY as u64);
+        let (fh, _) = filesystem.open(req(), Path::new("/body"), libc::O_RDWR as u32).unwrap();
+        assert_eq!(filesystem.write(req(), Path::new("/body"), fh, 1, vec![9; 2], 0), Ok(2));
+        filesystem.release(req(), Path::new("/body"), fh, 0, 0, false).unwrap();
+        assert_eq!(fs::read(dir.path().join("body")).unwrap()[1..3], [9, 9]);
+
+        // The virtual file can't be changed or replaced.
+        let f = Path::new("/f");
+        assert_eq!(filesystem.open(req(), f, libc::O_WRONLY as u32), Err(libc::EROFS));
+        assert_eq!(filesystem.unlink(req(), root, OsStr::new("f")), Err(libc::EROFS));
+        assert_eq!(filesystem.chmod(req(), f, None, 0o600), Err(libc::EROFS));
+        assert_eq!(filesystem.rename(req(), root, OsStr::new("body"), root, OsStr::new("f")), Err(libc::EROFS));
+        assert!(dir.path().join("body").exists());
+    }
+
+    #[test]
+    fn wrong_key_is_caught_before_mounting() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut filesystem, _, _) = fixture(dir.path());
+        assert!(filesystem.check_keys());
+
+        let wrong = KeySource::Literal(fernet::Fernet::generate_key().into()).keys(None).unwrap();
+        filesystem.decryptor = Decryptor::new(BTreeMap::from([(OsString::from("f"), wrong)]));
+        assert!(!filesystem.check_keys());
+    }
+
+    #[test]
+    fn overlay_only_directories_have_no_handle() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut filesystem, mut manifest, _) = fixture(dir.path());
+        manifest.path = Some(PathBuf::from("sub/f"));
+        filesystem.files = BTreeMap::from([(OsString::from("f"), manifest)]);
+        filesystem.namespace = Namespace::new(&filesystem.files).unwrap();
+        let sub = Path::new("/sub");
+
+        let (fh, _) = filesystem.opendir(req(), sub, 0).unwrap();
+        assert_eq!(fh, 0);
+        let names: Vec<_> = filesystem.readdir(req(), sub, fh).unwrap().into_iter().map(|e| e.name).collect();
+        assert_eq!(names, [".", "..", "f"]);
+        assert_eq!(filesystem.fsyncdir(req(), sub, fh, false), Ok(()));
+        assert_eq!(filesystem.releasedir(req(), sub, fh, 0), Ok(()));
+    }
+
+    #[test]
+    fn truncated_chunk_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let (filesystem, manifest, _) = fixture(dir.path());
+        fs::write(dir.path().join("tail"), [0; TAIL - 1]).unwrap();
+        let e = filesystem.read_virtual(OsStr::new("f"), &manifest, SIZE as u64 - 5, 5).unwrap_err();
+        assert_eq!(e.errno(), libc::EIO);
+    }
+}
@@ -0,0 +1,248 @@
+// ReadAhead :: Sequential-access detection and background prefetch.
+//
+// Each open file handle has a stream, which remembers where its last read ended. Once several
+// reads in a row each start where the previous one stopped, the stream is treated as sequential
+// and the data ahead of it is fetched into the block cache by background workers. The window
+// starts small and doubles with every further sequential read, up to a per-stream cap, and a
+// global budget bounds how many bytes are being prefetched at once.
+//
+
+use std::ffi::OsStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::fetch::Fetcher;
+use crate::manifest::{Chunk, Encoding, Manifest};
+
+/// Number of back-to-back reads needed before a stream counts as sequential.
+const SEQUENTIAL_THRESHOLD: u32 = 2;
+
+#[derive(Clone, Copy, Debug)]
+pub struct ReadAheadConfig {
+    /// Window used when a stream is first detected as sequential.
+    pub initial_window: u64,
+    /// Largest window any one stream may grow to.
+    pub max_window: u64,
+    /// Most bytes being prefetched at once, across all streams.
+    pub max_in_flight: u64,
+    /// Number of background fetch threads.
+    pub workers: usize,
+}
+
+/// The access history of one open file handle.
+#[derive(Default)]
+pub struct Stream {
+    /// Offset just past the end of the previous read.
+    next: u64,
+    /// Number of consecutive reads which started at `next`.
+    run: u32,
+    window: u64,
+    /// Everything before this offset has already been queued for prefetch.
+    queued_to: u64,
+}
+
+struct Job {
+    chunk: Chunk,
+    /// Range of the stored object to fetch.
+    start: u64,
+    end: u64,
+    /// Bytes of the prefetch budget this job holds.
+    reserved: u64,
+}
+
+pub struct ReadAhead {
+    config: ReadAheadConfig,
+    in_flight: Arc<AtomicU64>,
+    jobs: Sender<Job>,
+}
+
+impl ReadAhead {
+    pub fn new(fetcher: Arc<Fetcher>, config: ReadAheadConfig) -> ReadAhead {
+        let (jobs, queue) = mpsc::channel();
+        let queue = Arc::new(Mutex::new(queue));
+        let in_flight = Arc::new(AtomicU64::new(0));
+        for i in 0..config.workers {
+            let fetcher = Arc::clone(&fetcher);
+            let queue = Arc::clone(&queue);
+            let in_flight = Arc::clone(&in_flight);
+            thread::Builder::new()
+                .name(format!("readahead-{}", i))
+                .spawn(move || worker(&fetcher, &queue, &in_flight))
+                .expect("failed to spawn read-ahead thread");
+        }
+        ReadAhead {
+            config,
+            in_flight,
+            jobs,
+        }
+    }
+
+    /// Record a read of `size` bytes at `offset` of virtual file `name` and, if the stream is
+    /// sequential, queue the data ahead of it for prefetch.
+    pub fn on_read(&self, stream: &mut Stream, name: &OsStr, manifest: &Manifest, offset: u64, size: u32) {
+        if offset == stream.next && offset != 0 {
+            stream.run += 1;
+        } else {
+            *stream = Stream::default();
+        }
+        stream.next = offset.saturating_add(u64::from(size));
+        if stream.run < SEQUENTIAL_THRESHOLD {
+            return;
+        }
+
+        stream.window = if stream.window == 0 {
+            self.config.initial_window
+        } else {
+            (stream.window * 2).min(self.config.max_window)
+        };
+        let target = stream.next.saturating_add(stream.window).min(manifest.size());
+        let start = stream.queued_to.max(stream.next);
+        if start >= target {
+            return;
+        }
+        let len = self.reserve(target - start);
+        if len == 0 {
+            return;
+        }
+        stream.queued_to = start + len;
+
+        debug!("readahead: {:?} {:#x} bytes @ {:#x}", name, len, start);
+        for segment in manifest.segments(start, len as u32) {
+            let reserved = segment.end - segment.start + 1;
+            // Fernet chunks can't be read in pieces; AEAD ones are fetched as sealed blocks.
+            if segment.chunk.encoding == Encoding::Fernet {
+                self.in_flight.fetch_sub(reserved, Ordering::SeqCst);
+                continue;
+            }
+            let (start, end) = segment.chunk.stored_range(segment.start, segment.end);
+            let job = Job {
+                chunk: segment.chunk.clone(),
+                start,
+                end,
+                reserved,
+            };
+            if self.jobs.send(job).is_err() {
+                self.in_flight.fetch_sub(reserved, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// Claim up to `wanted` bytes of the global prefetch budget, returning how much was granted.
+    fn reserve(&self, wanted: u64) -> u64 {
+        let wanted = wanted.min(u64::from(u32::MAX));
+        let mut granted = 0;
+        let _ = self.in_flight.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |current| {
+            granted = wanted.min(self.config.max_in_flight.saturating_sub(current));
+            Some(current + granted)
+        });
+        granted
+    }
+}
+
+fn worker(fetcher: &Fetcher, queue: &Mutex<Receiver<Job>>, in_flight: &AtomicU64) {
+    loop {
+        let job = match queue.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        if let Err(e) = fetcher.prefetch(&job.chunk, job.start, job.end) {
+            warn!("readahead: chunk {} {:#x}-{:#x}: {}", job.chunk.id, job.start, job.end, e);
+        }
+        in_flight.fetch_sub(job.reserved, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const READ: u32 = 4096;
+
+    /// Read-ahead whose jobs are left in the returned queue instead of being fetched.
+    fn readahead(initial_window: u64, max_window: u64, max_in_flight: u64) -> (ReadAhead, Receiver<Job>) {
+        let (jobs, queue) = mpsc::channel();
+        let config = ReadAheadConfig { initial_window, max_window, max_in_flight, workers: 0 };
+        (ReadAhead { config, in_flight: Arc::new(AtomicU64::new(0)), jobs }, queue)
+    }
+
+    fn manifest() -> Manifest {
+        serde_json::from_value(serde_json::json!({
+            "version": 1,
+            "chunks": [
+                {"id": "a", "start": 0, "end": 65535, "size": 65536},
+                {"id": "b", "start": 65536, "end": 1048575, "size": 983040},
+            ],
+        })).unwrap()
+    }
+
+    /// Read the next `READ` bytes of `stream` sequentially, from `offset` on.
+    fn read_at(readahead: &ReadAhead, stream: &mut Stream, offset: u64) {
+        readahead.on_read(stream, OsStr::new("f"), &manifest(), offset, READ);
+    }
+
+    #[test]
+    fn sequential_streams_are_detected() {
+        let (readahead, queue) = readahead(8192, 65536, 1 << 20);
+        let mut stream = Stream::default();
+        read_at(&readahead, &mut stream, 0);
+        read_at(&readahead, &mut stream, 4096);
+        assert!(queue.try_recv().is_err());
+
+        read_at(&readahead, &mut stream, 8192);
+        let job = queue.try_recv().unwrap();
+        assert_eq!((job.chunk.id.as_str(), job.start, job.end), ("a", 12288, 12288 + 8192 - 1));
+        assert!(queue.try_recv().is_err());
+
+        // A jump elsewhere starts over.
+        read_at(&readahead, &mut stream, 500_000);
+        read_at(&readahead, &mut stream, 504_096);
+        assert_eq!((stream.run, stream.window), (1, 0));
+        assert!(queue.try_recv().is_err());
+    }
+
+    #[test]
+    fn window_doubles_up_to_its_cap() {
+        let (readahead, queue) = readahead(8192, 32768, 1 << 20);
+        let mut stream = Stream::default();
+        let mut windows = vec![];
+        for i in 0..7 {
+            read_at(&readahead, &mut stream, i * u64::from(READ));
+            windows.push(stream.window);
+            if stream.window > 0 {
+                assert_eq!(stream.queued_to, stream.next + stream.window);
+            }
+        }
+        assert_eq!(windows, [0, 0, 8192, 16384, 32768, 32768, 32768]);
+
+        // Nothing is queued twice: the jobs cover everything up to `queued_to` exactly once.
+        let mut expected_start = 3 * u64::from(READ);
+        for job in queue.try_iter() {
+            let start = job.start + if job.chunk.id == "b" { 65536 } else { 0 };
+            assert_eq!(start, expected_start);
+            expected_start = start + (job.end - job.start + 1);
+        }
+        assert_eq!(expected_start, stream.queued_to);
+    }
+
+    #[test]
+    fn in_flight_bytes_stay_within_budget() {
+        let (readahead, queue) = readahead(8192, 65536, 20000);
+        let mut streams: Vec<Stream> = (0..3).map(|_| Stream::default()).collect();
+        for i in 0..5 {
+            for (n, stream) in streams.iter_mut().enumerate() {
+                read_at(&readahead, stream, n as u64 * 200_000 + i * u64::from(READ));
+            }
+        }
+        let queued: Vec<Job> = queue.try_iter().collect();
+        let reserved: u64 = queued.iter().map(|job| job.reserved).sum();
+        assert_eq!(reserved, 20000);
+        assert_eq!(readahead.in_flight.load(Ordering::SeqCst), 20000);
+
+        // Finishing jobs frees the budget for more.
+        readahead.in_flight.fetch_sub(reserved, Ordering::SeqCst);
+        read_at(&readahead, &mut streams[0], 5 * u64::from(READ));
+        assert!(queue.try_recv().is_ok());
+    }
+}
@@ -0,0 +1,125 @@
+// StandIn :: A local HTTP server that plays Google's part in tests.
+//
+// It answers each connection with the next of a fixed list of canned responses, closing the
+// connection afterwards, and hands every request it got back to the test to inspect.
+//
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+pub struct StandIn {
+    /// `http://127.0.0.1:<port>`, without a trailing slash.
+    pub url: String,
+    requests: Receiver<Request>,
+}
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// Header names are lowercased.
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+
+    /// The fields of an `application/x-www-form-urlencoded` body.
+    pub fn form(&self) -> HashMap<String, String> {
+        String::from_utf8_lossy(&self.body).split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(name, value)| (decode(name), decode(value)))
+            .collect()
+    }
+}
+
+fn decode(s: &str) -> String {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        match b {
+            b'+' => bytes.push(b' '),
+            b'%' if tail.len() >= 2 => {
+                let hex = std::str::from_utf8(&tail[..2]).unwrap();
+                bytes.push(u8::from_str_radix(hex, 16).unwrap());
+                rest = &tail[2..];
+                continue;
+            }
+            b => bytes.push(b),
+        }
+        rest = tail;
+    }
+    String::from_utf8(bytes).unwrap()
+}
+
+/// A complete HTTP/1.1 response with status `status`, extra `headers` and `body`.
+pub fn response(status: &str, headers: &[(&str, &str)], body: impl AsRef<[u8]>) -> Vec<u8> {
+    let body = body.as_ref();
+    let mut text = format!("HTTP/1.1 {}\r\nConnection: close\r\n", status);
+    if !headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("content-length")) {
+        text += &format!("Content-Length: {}\r\n", body.len());
+    }
+    for (name, value) in headers {
+        text += &format!("{}: {}\r\n", name, value);
+    }
+    text += "\r\n";
+    let mut bytes = text.into_bytes();
+    bytes.extend_from_slice(body);
+    bytes
+}
+
+/// A 200 response carrying `body` as JSON.
+pub fn json(body: serde_json::Value) -> Vec<u8> {
+    response("200 OK", &[("Content-Type", "application/json")], body.to_string())
+}
+
+impl StandIn {
+    /// Start serving `responses`, one per connection, in order.
+    pub fn serve(responses: Vec<Vec<u8>>) -> StandIn {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (sender, requests) = mpsc::channel();
+        thread::spawn(move || {
+            for response in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let mut parts = line.split_whitespace();
+                let method = parts.next().unwrap_or_default().to_owned();
+                let path = parts.next().unwrap_or_default().to_owned();
+
+                let mut headers = HashMap::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    match line.trim_end().split_once(':') {
+                        Some((name, value)) => headers.insert(name.to_lowercase(), value.trim().to_owned()),
+                        None => break,
+                    };
+                }
+                let length = headers.get("content-length").map_or(0, |n| n.parse().unwrap());
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+
+                // Recorded before answering, so the test sees it as soon as its call returns.
+                if sender.send(Request { method, path, headers, body }).is_err() {
+                    return;
+                }
+                // The client may give up on a bad response before reading all of it.
+                let _ = reader.into_inner().write_all(&response);
+            }
+        });
+        StandIn { url, requests }
+    }
+
+    /// Every request received so far, in order.
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.try_iter().collect()
+    }
+}
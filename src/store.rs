@@ -0,0 +1,300 @@
+// ChunkStore :: Where chunk objects are fetched from.
+//
+// The filesystem only ever asks for byte ranges of chunk objects by ID; implementations decide
+// what an ID refers to. `DriveStore` (in drive.rs) talks to Google Drive, and `LocalStore` reads
+// chunks from files in a local directory.
+//
+// Chunks are expected to stay put while mounted. Each store remembers the version of every chunk
+// as first seen and fails reads with `FsError::ChunkChanged` once a chunk has been replaced, so
+// that a file is never stitched together from old and new data.
+//
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::RwLock;
+use std::time::UNIX_EPOCH;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::FsError;
+
+pub type StoreResult<T> = Result<T, FsError>;
+
+/// What the backend reports about a stored chunk object.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ChunkMeta {
+    /// Size of the object as stored, in bytes.
+    pub size: u64,
+    /// Hex MD5 of the stored object, if the backend computes one.
+    pub md5: Option<String>,
+    /// Opaque modification stamp; it changes whenever the object is replaced.
+    pub modified: Option<String>,
+}
+
+impl ChunkMeta {
+    /// Whether `other` describes the same version of the object, going by whatever both know.
+    pub fn same_version(&self, other: &ChunkMeta) -> bool {
+        let agree = |a: &Option<String>, b: &Option<String>| match (a, b) {
+            (Some(a), Some(b)) => a == b,
+            _ => true,
+        };
+        self.size == other.size && agree(&self.md5, &other.md5) && agree(&self.modified, &other.modified)
+    }
+}
+
+/// The version of each chunk as first seen by a store.
+///
+/// Looked up on every read but only written the first time each chunk is seen.
+#[derive(Default)]
+pub struct Versions {
+    seen: RwLock<HashMap<String, ChunkMeta>>,
+}
+
+impl Versions {
+    /// Return the version of `chunk_id` first seen, looking it up with `lookup` on first access
+    /// only.
+    pub fn record(&self, chunk_id: &str, lookup: impl FnOnce() -> StoreResult<ChunkMeta>) -> StoreResult<ChunkMeta> {
+        if let Some(first) = self.seen.read().unwrap().get(chunk_id) {
+            return Ok(first.clone());
+        }
+        let meta = lookup()?;
+        debug!("chunk {}: first seen as {:?}", chunk_id, meta);
+        Ok(self.seen.write().unwrap().entry(chunk_id.to_owned()).or_insert(meta).clone())
+    }
+
+    /// Forget the version of `chunk_id`, because this store replaced it.
+    pub fn forget(&self, chunk_id: &str) {
+        self.seen.write().unwrap().remove(chunk_id);
+    }
+
+    /// Compare `current` against the version first seen, recording it if there is none yet.
+    pub fn check(&self, chunk_id: &str, current: ChunkMeta) -> StoreResult<()> {
+        if let Some(first) = self.seen.read().unwrap().get(chunk_id) {
+            if first.same_version(&current) {
+                return Ok(());
+            }
+        }
+        let mut seen = self.seen.write().unwrap();
+        let first = seen.entry(chunk_id.to_owned()).or_insert_with(|| current.clone());
+        if first.same_version(&current) {
+            return Ok(());
+        }
+        error!("chunk {} changed on the backend while mounted: was {:?}, now {:?}", chunk_id, first, current);
+        Err(FsError::ChunkChanged { id: chunk_id.to_owned() })
+    }
+}
+
+pub trait ChunkStore: Send + Sync {
+    /// Read bytes `start..=end` of the object `chunk_id`.
+    ///
+    /// Fewer bytes are returned if the object ends before `end`.
+    fn read_range(&self, chunk_id: &str, start: u64, end: u64) -> StoreResult<Vec<u8>>;
+
+    /// Read the whole object `chunk_id`.
+    fn read_all(&self, chunk_id: &str) -> StoreResult<Vec<u8>>;
+
+    /// Replace the contents of the object `chunk_id`, keeping its ID.
+    fn write(&self, chunk_id: &str, data: &[u8]) -> StoreResult<()>;
+
+    /// Look up size and version information for `chunk_id` without reading it.
+    fn metadata(&self, chunk_id: &str) -> StoreResult<ChunkMeta>;
+
+    /// The version of `chunk_id` which reads are held to: the one first seen while mounted.
+    fn version(&self, chunk_id: &str) -> StoreResult<ChunkMeta>;
+}
+
+/// Chunks stored as files in one local directory, each named by its chunk ID.
+pub struct LocalStore {
+    root: PathBuf,
+    versions: Versions,
+}
+
+impl LocalStore {
+    pub fn new(root: impl Into<PathBuf>) -> LocalStore {
+        LocalStore {
+            root: root.into(),
+            versions: Versions::default(),
+        }
+    }
+
+    fn chunk_path(&self, chunk_id: &str) -> io::Result<PathBuf> {
+        // The ID must name a file directly inside the root, not somewhere else on the system.
+        let mut components = Path::new(chunk_id).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) => Ok(self.root.join(chunk_id)),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                    format!("invalid chunk id {:?}", chunk_id))),
+        }
+    }
+}
+
+impl ChunkStore for LocalStore {
+    fn read_range(&self, chunk_id: &str, start: u64, end: u64) -> StoreResult<Vec<u8>> {
+        self.versions.check(chunk_id, self.metadata(chunk_id)?)?;
+        let mut file = File::open(self.chunk_path(chunk_id)?)?;
+        file.seek(SeekFrom::Start(start))?;
+        let mut buf = vec![];
+        file.take(end.saturating_sub(start) + 1).read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn read_all(&self, chunk_id: &str) -> StoreResult<Vec<u8>> {
+        self.versions.check(chunk_id, self.metadata(chunk_id)?)?;
+        Ok(std::fs::read(self.chunk_path(chunk_id)?)?)
+    }
+
+    fn write(&self, chunk_id: &str, data: &[u8]) -> StoreResult<()> {
+        let path = self.chunk_path(chunk_id)?;
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, &path)?;
+        self.versions.forget(chunk_id);
+        Ok(())
+    }
+
+    fn metadata(&self, chunk_id: &str) -> StoreResult<ChunkMeta> {
+        let meta = std::fs::metadata(self.chunk_path(chunk_id)?)?;
+        let modified = meta.modified().ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| format!("{}.{:09}", d.as_secs(), d.subsec_nanos()));
+        Ok(ChunkMeta {
+            size: meta.len(),
+            md5: None,
+            modified,
+        })
+    }
+
+    fn version(&self, chunk_id: &str) -> StoreResult<ChunkMeta> {
+        self.versions.record(chunk_id, || self.metadata(chunk_id))
+    }
+}
+
+/// Chunks in a local directory, for tests: counts reads and how many are in progress at once.
+///
+/// Reads can be slowed down, and made to wait for each other: with `together` set, each one
+/// holds on until that many reads have been in progress at the same time (or a few seconds have
+/// passed), so that reads which should overlap are sure to, however the threads are scheduled.
+#[cfg(test)]
+pub(crate) struct CountingStore {
+    inner: LocalStore,
+    latency: std::time::Duration,
+    together: usize,
+    reads: std::sync::atomic::AtomicUsize,
+    reading: std::sync::atomic::AtomicUsize,
+    most_reading: std::sync::atomic::AtomicUsize,
+}
+
+#[cfg(test)]
+impl CountingStore {
+    pub fn new(root: impl Into<PathBuf>) -> CountingStore {
+        CountingStore {
+            inner: LocalStore::new(root),
+            latency: std::time::Duration::ZERO,
+            together: 0,
+            reads: Default::default(),
+            reading: Default::default(),
+            most_reading: Default::default(),
+        }
+    }
+
+    /// Make every read take at least `latency`, as it would from a remote backend.
+    pub fn latency(self, latency: std::time::Duration) -> CountingStore {
+        CountingStore { latency, ..self }
+    }
+
+    /// Hold every read until `together` reads have been in progress at once.
+    pub fn together(self, together: usize) -> CountingStore {
+        CountingStore { together, ..self }
+    }
+
+    fn counted<T>(&self, read: impl FnOnce() -> T) -> T {
+        use std::sync::atomic::Ordering;
+        use std::time::{Duration, Instant};
+
+        self.reads.fetch_add(1, Ordering::SeqCst);
+        let reading = self.reading.fetch_add(1, Ordering::SeqCst) + 1;
+        self.most_reading.fetch_max(reading, Ordering::SeqCst);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while self.most_reading.load(Ordering::SeqCst) < self.together && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        std::thread::sleep(self.latency);
+        let result = read();
+        self.reading.fetch_sub(1, Ordering::SeqCst);
+        result
+    }
+
+    /// Reads so far, ranged or whole.
+    pub fn reads(&self) -> usize {
+        self.reads.load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    /// The most reads that were in progress at once.
+    pub fn most_reading(&self) -> usize {
+        self.most_reading.load(std::sync::atomic::Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+impl ChunkStore for CountingStore {
+    fn read_range(&self, chunk_id: &str, start: u64, end: u64) -> StoreResult<Vec<u8>> {
+        self.counted(|| self.inner.read_range(chunk_id, start, end))
+    }
+
+    fn read_all(&self, chunk_id: &str) -> StoreResult<Vec<u8>> {
+        self.counted(|| self.inner.read_all(chunk_id))
+    }
+
+    fn write(&self, chunk_id: &str, data: &[u8]) -> StoreResult<()> {
+        self.inner.write(chunk_id, data)
+    }
+
+    fn metadata(&self, chunk_id: &str) -> StoreResult<ChunkMeta> {
+        self.inner.metadata(chunk_id)
+    }
+
+    fn version(&self, chunk_id: &str) -> StoreResult<ChunkMeta> {
+        self.inner.version(chunk_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::libc_extras::libc;
+    use std::fs;
+
+    #[test]
+    fn chunk_replaced_while_mounted_is_stale() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("c"), [1; 100]).unwrap();
+        let store = LocalStore::new(dir.path());
+        assert_eq!(store.read_range("c", 0, 9).unwrap(), [1; 10]);
+
+        // Replaced behind the store's back, not through `write`.
+        fs::write(dir.path().join("c"), [2; 120]).unwrap();
+        assert_eq!(store.read_range("c", 0, 9).unwrap_err().errno(), libc::ESTALE);
+        assert_eq!(store.read_all("c").unwrap_err().errno(), libc::ESTALE);
+
+        // A chunk the store replaces itself is not.
+        store.write("c", &[3; 50]).unwrap();
+        assert_eq!(store.read_all("c").unwrap(), [3; 50]);
+    }
+
+    #[test]
+    fn versions_agree_on_what_both_know() {
+        let versions = Versions::default();
+        let meta = |size, md5: Option<&str>| ChunkMeta { size, md5: md5.map(str::to_owned), modified: None };
+        versions.check("c", meta(10, Some("aa"))).unwrap();
+        versions.check("c", meta(10, None)).unwrap();
+        assert!(matches!(versions.check("c", meta(10, Some("bb"))), Err(FsError::ChunkChanged { .. })));
+        assert!(matches!(versions.check("c", meta(11, None)), Err(FsError::ChunkChanged { .. })));
+        versions.forget("c");
+        versions.check("c", meta(11, None)).unwrap();
+    }
+}